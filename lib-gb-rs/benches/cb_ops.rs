@@ -0,0 +1,32 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lib_gb_rs::GameBoy;
+
+// BIT/SET/RES on registers and (HL), forever
+const CB_LOOP: [u8; 21] = [
+    0x21, 0x00, 0xC0, // LD HL, 0xC000
+    0xCB, 0x47, // BIT 0, A
+    0xCB, 0xC8, // SET 1, B
+    0xCB, 0x91, // RES 2, C
+    0xCB, 0x7E, // BIT 7, (HL)
+    0xCB, 0xDE, // SET 3, (HL)
+    0xCB, 0xA6, // RES 4, (HL)
+    0xCB, 0x7A, // BIT 7, D
+    0xCB, 0xE3, // SET 4, E
+    0x18, 0xEE, // JR -18
+];
+
+fn cb_loop(c: &mut Criterion) {
+    let mut gb = GameBoy::diagnostic(CB_LOOP.to_vec()).unwrap();
+    c.bench_function("cb_loop_10k_operations", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                let (events, res) = gb.execute_operation();
+                res.unwrap();
+                black_box(events);
+            }
+        })
+    });
+}
+
+criterion_group!(benches, cb_loop);
+criterion_main!(benches);
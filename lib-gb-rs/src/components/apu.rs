@@ -0,0 +1,481 @@
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::{Addressable, Model};
+
+const NR10: u16 = 0xFF10;
+const NR52: u16 = 0xFF26;
+const WAVE_RAM_START: u16 = 0xFF30;
+const WAVE_RAM_END: u16 = 0xFF3F;
+
+// Bits that always read back as 1, write-only or unused, for NR10 up to NR51
+const READ_MASKS: [u8; 0x16] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, // NR50, NR51
+];
+
+// Frame sequencer runs at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u16 = 8192;
+
+#[derive(Debug, Default, Clone)]
+struct Channel {
+    enabled: bool,
+    dac_enabled: bool,
+    length_max: u16,
+    length_counter: u16,
+    length_enabled: bool,
+}
+
+impl Channel {
+    fn new(length_max: u16) -> Self {
+        Self {
+            length_max,
+            ..Self::default()
+        }
+    }
+
+    fn load_length(&mut self, length_data: u8) {
+        self.length_counter = self.length_max - (length_data as u16);
+    }
+
+    fn set_dac(&mut self, enabled: bool) {
+        self.dac_enabled = enabled;
+        if !enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self) {
+        if self.length_counter == 0 {
+            self.length_counter = self.length_max;
+        }
+        self.enabled = self.dac_enabled;
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length_counter > 0 {
+            self.length_counter -= 1;
+            if self.length_counter == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+// Only the register file, power, length counters and the raw channel 3 output so far, nothing is
+// mixed into samples.
+#[derive(Debug, Clone)]
+pub struct Apu {
+    model: Model,
+    registers: [u8; 0x16],
+    wave_ram: [u8; 0x10],
+    // Channel 3 position in the 32 samples of wave RAM
+    wave_timer: u16,
+    wave_position: u8,
+    wave_fetched_this_tick: bool,
+    powered: bool,
+    channels: [Channel; 4],
+    frame_sequencer_clock: u16,
+    frame_sequencer_step: u8,
+}
+
+impl Apu {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            ..Self::default()
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.tick_wave();
+        self.frame_sequencer_clock += 1;
+        if self.frame_sequencer_clock == FRAME_SEQUENCER_PERIOD {
+            self.frame_sequencer_clock = 0;
+            if self.frame_sequencer_step % 2 == 0 {
+                self.channels.iter_mut().for_each(Channel::clock_length);
+            }
+            self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+        }
+    }
+
+    fn tick_wave(&mut self) {
+        self.wave_fetched_this_tick = false;
+        if !self.channels[2].enabled {
+            return;
+        }
+        self.wave_timer = self.wave_timer.saturating_sub(1);
+        if self.wave_timer == 0 {
+            self.wave_timer = self.wave_period();
+            self.wave_position = (self.wave_position + 1) % 32;
+            self.wave_fetched_this_tick = true;
+        }
+    }
+
+    // Cycles per wave RAM sample. The 32 samples play at 65536 / (2048 - period) Hz.
+    fn wave_period(&self) -> u16 {
+        let period = (((self.registers[0x0E] & 0b111) as u16) << 8) | self.registers[0x0D] as u16;
+        (2048 - period) * 2
+    }
+
+    // Channel 3's DAC input: the current sample shifted by the NR32 output level, 0 while off
+    pub fn wave_output(&self) -> u8 {
+        if !self.channels[2].enabled {
+            return 0;
+        }
+        let byte = self.wave_ram[(self.wave_position / 2) as usize];
+        let sample = if self.wave_position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        match (self.registers[0x0C] >> 5) & 0b11 {
+            0 => 0,
+            level => sample >> (level - 1),
+        }
+    }
+
+    // While channel 3 plays, wave RAM accesses go to the byte it is reading. On DMG only right
+    // when the channel reads it, any other time reads give 0xFF and writes are ignored.
+    fn wave_ram_index(&self, address: u16) -> Option<usize> {
+        if !self.channels[2].enabled {
+            Some((address - WAVE_RAM_START) as usize)
+        } else if self.model.is_cgb() || self.wave_fetched_this_tick {
+            Some((self.wave_position / 2) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn read_nr52(&self) -> u8 {
+        let status = self
+            .channels
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.enabled)
+            .fold(0, |acc, (i, _)| acc | (1 << i));
+        let power = if self.powered { 0x80 } else { 0 };
+
+        power | 0b01110000 | status
+    }
+
+    fn write_nr52(&mut self, value: u8) {
+        let powered = value & 0x80 > 0;
+        if self.powered && !powered {
+            self.registers = [0; 0x16];
+            self.channels
+                .iter_mut()
+                .for_each(|c| *c = Channel::new(c.length_max));
+        }
+        self.powered = powered;
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        self.registers[(address - NR10) as usize] = value;
+        match address {
+            // NRx1, length data
+            0xFF11 | 0xFF16 | 0xFF20 => {
+                let channel = Self::channel_index(address);
+                self.channels[channel].load_length(value & 0b00111111);
+            }
+            0xFF1B => self.channels[2].load_length(value),
+            // NRx2, DAC enabled if any of the upper 5 bits is set
+            0xFF12 | 0xFF17 | 0xFF21 => {
+                let channel = Self::channel_index(address);
+                self.channels[channel].set_dac(value & 0b11111000 > 0);
+            }
+            0xFF1A => self.channels[2].set_dac(value & 0x80 > 0),
+            // NRx4, trigger and length enable
+            0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => {
+                let index = Self::channel_index(address);
+                let channel = &mut self.channels[index];
+                channel.length_enabled = value & 0b01000000 > 0;
+                if value & 0x80 > 0 {
+                    channel.trigger();
+                    if index == 2 {
+                        self.wave_timer = self.wave_period();
+                        self.wave_position = 0;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn channel_index(address: u16) -> usize {
+        match address {
+            0xFF10..=0xFF14 => 0,
+            0xFF15..=0xFF19 => 1,
+            0xFF1A..=0xFF1E => 2,
+            0xFF1F..=0xFF23 => 3,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            model: Model::default(),
+            registers: [0; 0x16],
+            wave_ram: [0; 0x10],
+            wave_timer: 0,
+            wave_position: 0,
+            wave_fetched_this_tick: false,
+            powered: false,
+            channels: [
+                Channel::new(64),
+                Channel::new(64),
+                Channel::new(256),
+                Channel::new(64),
+            ],
+            frame_sequencer_clock: 0,
+            frame_sequencer_step: 0,
+        }
+    }
+}
+
+impl Addressable for Apu {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            NR10..=0xFF25 => {
+                let index = (address - NR10) as usize;
+                Some(self.registers[index] | READ_MASKS[index])
+            }
+            NR52 => Some(self.read_nr52()),
+            0xFF27..=0xFF2F => Some(0xFF),
+            WAVE_RAM_START..=WAVE_RAM_END => match self.wave_ram_index(address) {
+                Some(index) => Some(self.wave_ram[index]),
+                None => Some(0xFF),
+            },
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Option<()> {
+        match address {
+            NR10..=0xFF25 => {
+                if self.powered {
+                    self.write_register(address, value);
+                }
+                Some(())
+            }
+            NR52 => {
+                self.write_nr52(value);
+                Some(())
+            }
+            0xFF27..=0xFF2F => Some(()),
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                if let Some(index) = self.wave_ram_index(address) {
+                    self.wave_ram[index] = value;
+                }
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SaveState for Apu {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.registers);
+        w.bytes(&self.wave_ram);
+        w.u16(self.wave_timer);
+        w.u8(self.wave_position);
+        w.bool(self.wave_fetched_this_tick);
+        w.bool(self.powered);
+        for c in &self.channels {
+            w.bool(c.enabled);
+            w.bool(c.dac_enabled);
+            w.u16(c.length_counter);
+            w.bool(c.length_enabled);
+        }
+        w.u16(self.frame_sequencer_clock);
+        w.u8(self.frame_sequencer_step);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.fill(&mut self.registers)?;
+        r.fill(&mut self.wave_ram)?;
+        self.wave_timer = r.u16()?;
+        self.wave_position = r.u8()?;
+        self.wave_fetched_this_tick = r.bool()?;
+        self.powered = r.bool()?;
+        for c in &mut self.channels {
+            c.enabled = r.bool()?;
+            c.dac_enabled = r.bool()?;
+            c.length_counter = r.u16()?;
+            c.length_enabled = r.bool()?;
+        }
+        self.frame_sequencer_clock = r.u16()?;
+        self.frame_sequencer_step = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nr52_channel_status() {
+        let mut apu = Apu::default();
+        assert_eq!(apu.read(NR52), Some(0x70));
+
+        apu.write(NR52, 0x80).unwrap();
+        assert_eq!(apu.read(NR52), Some(0xF0));
+
+        // Channel 1 DAC on, trigger
+        apu.write(0xFF12, 0xF0).unwrap();
+        apu.write(0xFF14, 0x80).unwrap();
+        assert_eq!(apu.read(NR52), Some(0xF1));
+
+        // Channel 2 without DAC doesn't start
+        apu.write(0xFF19, 0x80).unwrap();
+        assert_eq!(apu.read(NR52), Some(0xF1));
+
+        apu.write(NR52, 0x00).unwrap();
+        assert_eq!(apu.read(NR52), Some(0x70));
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut apu = Apu::default();
+        apu.write(NR52, 0x80).unwrap();
+        apu.write(0xFF17, 0xF0).unwrap();
+        // Length 64 - 62 = 2 frame sequencer length clocks
+        apu.write(0xFF16, 62).unwrap();
+        apu.write(0xFF19, 0xC0).unwrap();
+        assert_eq!(apu.read(NR52), Some(0xF2));
+
+        // Length is clocked on every other step: after the 1st and 3rd step
+        for _ in 0..FRAME_SEQUENCER_PERIOD {
+            apu.tick();
+        }
+        assert_eq!(apu.read(NR52), Some(0xF2));
+        for _ in 0..FRAME_SEQUENCER_PERIOD * 2 {
+            apu.tick();
+        }
+        assert_eq!(apu.read(NR52), Some(0xF0));
+    }
+
+    #[test]
+    fn read_masks() {
+        let mut apu = Apu::default();
+        apu.write(NR52, 0x80).unwrap();
+
+        apu.write(0xFF13, 0x12).unwrap();
+        apu.write(0xFF14, 0x47).unwrap();
+        assert_eq!(apu.read(0xFF13), Some(0xFF));
+        assert_eq!(apu.read(0xFF14), Some(0xFF));
+        apu.write(0xFF14, 0x07).unwrap();
+        assert_eq!(apu.read(0xFF14), Some(0xBF));
+
+        apu.write(0xFF11, 0x82).unwrap();
+        assert_eq!(apu.read(0xFF11), Some(0xBF));
+        apu.write(0xFF12, 0x53).unwrap();
+        assert_eq!(apu.read(0xFF12), Some(0x53));
+        assert_eq!(apu.read(0xFF15), Some(0xFF));
+        assert_eq!(apu.read(0xFF27), Some(0xFF));
+    }
+
+    #[test]
+    fn nrx4_trigger_not_readable() {
+        let mut apu = Apu::default();
+        apu.write(NR52, 0x80).unwrap();
+        for address in [0xFF14, 0xFF19, 0xFF1E, 0xFF23] {
+            // Only length enable reads back, the trigger reads 1 like the unused bits
+            apu.write(address, 0xFF).unwrap();
+            assert_eq!(apu.read(address), Some(0xFF));
+            apu.write(address, 0x80).unwrap();
+            assert_eq!(apu.read(address), Some(0xBF));
+            apu.write(address, 0x40).unwrap();
+            assert_eq!(apu.read(address), Some(0xFF));
+        }
+    }
+
+    fn playing_wave_channel(model: Model) -> Apu {
+        let mut apu = Apu::new(model);
+        apu.write(NR52, 0x80).unwrap();
+        for (i, address) in (WAVE_RAM_START..=WAVE_RAM_END).enumerate() {
+            apu.write(address, i as u8 * 0x11).unwrap();
+        }
+        apu.write(0xFF1A, 0x80).unwrap();
+        // Frequency 0x7FF, a new sample every 2 cycles
+        apu.write(0xFF1D, 0xFF).unwrap();
+        apu.write(0xFF1E, 0x87).unwrap();
+        apu
+    }
+
+    #[test]
+    fn wave_ram_conflict_dmg() {
+        let mut apu = playing_wave_channel(Model::Dmg);
+        // Sample 2, in the second byte, is fetched on the 4th cycle
+        for _ in 0..4 {
+            apu.tick();
+        }
+        assert_eq!(apu.read(0xFF3A), Some(0x11));
+
+        // Between fetches there is no access at all
+        apu.tick();
+        assert_eq!(apu.read(0xFF3A), Some(0xFF));
+        apu.write(0xFF30, 0xAB).unwrap();
+        apu.tick();
+        apu.write(0xFF30, 0xCD).unwrap();
+
+        apu.write(0xFF1A, 0x00).unwrap();
+        assert_eq!(apu.read(0xFF30), Some(0x00));
+        assert_eq!(apu.read(0xFF31), Some(0xCD));
+        assert_eq!(apu.read(0xFF32), Some(0x22));
+    }
+
+    #[test]
+    fn wave_pitch_and_length() {
+        let mut apu = Apu::default();
+        apu.write(NR52, 0x80).unwrap();
+        // Square wave: 16 samples of 15, 16 of 0
+        for (i, address) in (WAVE_RAM_START..=WAVE_RAM_END).enumerate() {
+            apu.write(address, if i < 8 { 0xFF } else { 0x00 }).unwrap();
+        }
+        let period = 0x700;
+        apu.write(0xFF1A, 0x80).unwrap();
+        // Length 256 - 192 = 64 steps at 256 Hz
+        apu.write(0xFF1B, 192).unwrap();
+        apu.write(0xFF1C, 0x20).unwrap();
+        apu.write(0xFF1D, period as u8).unwrap();
+        apu.write(0xFF1E, 0xC0 | (period >> 8) as u8).unwrap();
+
+        // One sample every 64 cycles, 65536 Hz
+        let samples: Vec<_> = (0..65536 / 4)
+            .map(|_| {
+                for _ in 0..64 {
+                    apu.tick();
+                }
+                apu.wave_output()
+            })
+            .collect();
+
+        // The first length clock is at 8192 cycles, then one every 16384
+        let silent_from = (FRAME_SEQUENCER_PERIOD as usize + 63 * 16384) / 64 - 1;
+        assert_eq!(samples[silent_from - 1], 15);
+        assert!(samples[silent_from..].iter().all(|s| *s == 0));
+        assert_eq!(apu.read(NR52), Some(0xF0));
+
+        let frequency = 65536 / (2048 - period);
+        let rising_edges = samples[..silent_from]
+            .windows(2)
+            .filter(|w| w[0] == 0 && w[1] > 0)
+            .count();
+        assert_eq!(rising_edges, frequency * silent_from / 65536);
+    }
+
+    #[test]
+    fn wave_ram_conflict_cgb() {
+        let mut apu = playing_wave_channel(Model::Cgb);
+        for _ in 0..5 {
+            apu.tick();
+        }
+        assert_eq!(apu.read(0xFF30), Some(0x11));
+    }
+}
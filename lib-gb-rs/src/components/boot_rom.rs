@@ -0,0 +1,108 @@
+use crate::components::cartridge::LoadError;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::Addressable;
+
+const BOOT_ROM_DISABLE: u16 = 0xFF50;
+const DMG_SIZE: usize = 0x0100;
+// Includes the 0x0100-0x01FF gap, where the cartridge header stays visible
+const CGB_SIZE: usize = 0x0900;
+
+// Mapped over the start of the cartridge until a non-zero write to 0xFF50, which can't be undone
+#[derive(Debug, Default, Clone)]
+pub struct BootRom {
+    rom: Vec<u8>,
+    mapped: bool,
+}
+
+impl BootRom {
+    pub fn new(rom: Vec<u8>) -> Result<Self, LoadError> {
+        match rom.len() {
+            DMG_SIZE | CGB_SIZE => Ok(Self { rom, mapped: true }),
+            len => Err(LoadError::InvalidBootRomSize(len)),
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mapped
+    }
+}
+
+impl Addressable for BootRom {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            BOOT_ROM_DISABLE => Some(0xFE | !self.mapped as u8),
+            0x0000..=0x00FF | 0x0200..=0x08FF if self.mapped => {
+                self.rom.get(address as usize).copied()
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Option<()> {
+        match address {
+            BOOT_ROM_DISABLE => {
+                if value != 0 {
+                    self.mapped = false;
+                }
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SaveState for BootRom {
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.mapped);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.mapped = r.bool()? && !self.rom.is_empty();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_until_disabled() {
+        let mut boot_rom = BootRom::new(vec![0x31; DMG_SIZE]).unwrap();
+        assert_eq!(boot_rom.read(0x00FF), Some(0x31));
+        assert_eq!(boot_rom.read(0x0100), None);
+        assert_eq!(boot_rom.read(BOOT_ROM_DISABLE), Some(0xFE));
+
+        boot_rom.write(BOOT_ROM_DISABLE, 0x00).unwrap();
+        assert!(boot_rom.is_mapped());
+        boot_rom.write(BOOT_ROM_DISABLE, 0x01).unwrap();
+        assert_eq!(boot_rom.read(0x0000), None);
+        assert_eq!(boot_rom.read(BOOT_ROM_DISABLE), Some(0xFF));
+    }
+
+    #[test]
+    fn cgb_boot_rom_leaves_header_visible() {
+        let boot_rom = BootRom::new(vec![0x31; CGB_SIZE]).unwrap();
+        assert_eq!(boot_rom.read(0x00FF), Some(0x31));
+        assert_eq!(boot_rom.read(0x0100), None);
+        assert_eq!(boot_rom.read(0x01FF), None);
+        assert_eq!(boot_rom.read(0x0200), Some(0x31));
+        assert_eq!(boot_rom.read(0x08FF), Some(0x31));
+        assert_eq!(boot_rom.read(0x0900), None);
+    }
+
+    #[test]
+    fn invalid_size() {
+        assert!(matches!(
+            BootRom::new(vec![0; 0x200]),
+            Err(LoadError::InvalidBootRomSize(0x200))
+        ));
+    }
+
+    #[test]
+    fn without_boot_rom() {
+        let boot_rom = BootRom::default();
+        assert!(!boot_rom.is_mapped());
+        assert_eq!(boot_rom.read(0x0000), None);
+    }
+}
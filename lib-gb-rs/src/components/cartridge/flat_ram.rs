@@ -0,0 +1,89 @@
+use crate::components::cartridge::{Cartridge, LoadError};
+use crate::{Addressable, KIB};
+
+const ROM_AREA: usize = 32 * KIB;
+const RAM_AREA: usize = 8 * KIB;
+
+// Not a real cartridge: the whole ROM and external RAM areas are writable and there's no header.
+#[derive(Debug, Clone)]
+pub struct FlatRamCartridge {
+    rom: Box<[u8; ROM_AREA]>,
+    ram: Box<[u8; RAM_AREA]>,
+}
+
+impl FlatRamCartridge {
+    pub fn new(program: &[u8], offset: u16) -> Result<Self, LoadError> {
+        let mut rom = Box::new([0; ROM_AREA]);
+        let start = offset as usize;
+        rom.get_mut(start..start + program.len())
+            .ok_or(LoadError::ProgramTooLarge(program.len(), offset))?
+            .copy_from_slice(program);
+        Ok(Self {
+            rom,
+            ram: Box::new([0; RAM_AREA]),
+        })
+    }
+}
+
+impl Addressable for FlatRamCartridge {
+    fn read(&self, address: u16) -> Option<u8> {
+        let a = address as usize;
+        match address {
+            0x0000..=0x7FFF => Some(self.rom[a]),
+            0xA000..=0xBFFF => Some(self.ram[a - 0xA000]),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Option<()> {
+        let a = address as usize;
+        match address {
+            0x0000..=0x7FFF => self.rom[a] = value,
+            0xA000..=0xBFFF => self.ram[a - 0xA000] = value,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+impl Cartridge for FlatRamCartridge {
+    fn current_rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn banking_snapshot(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn restore_banking(&mut self, _snapshot: &[u8]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_area_is_writable() {
+        let mut cartridge = FlatRamCartridge::new(&[0x12, 0x34], 0x0100).unwrap();
+        assert_eq!(cartridge.read(0x0101), Some(0x34));
+
+        cartridge.write(0x0000, 0x56).unwrap();
+        cartridge.write(0xBFFF, 0x78).unwrap();
+        assert_eq!(cartridge.read(0x0000), Some(0x56));
+        assert_eq!(cartridge.read(0xBFFF), Some(0x78));
+        assert_eq!(cartridge.read(0x8000), None);
+    }
+
+    #[test]
+    fn program_too_large() {
+        assert!(FlatRamCartridge::new(&[0; ROM_AREA - 0x0100], 0x0100).is_ok());
+        assert!(matches!(
+            FlatRamCartridge::new(&[0; ROM_AREA - 0x00FF], 0x0100),
+            Err(LoadError::ProgramTooLarge(0x7F01, 0x0100))
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+use crate::components::cartridge::LoadError;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MbcKind {
+    None,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CartridgeType {
+    RomOnly,
+    Mbc1,
+    Mbc1Ram,
+    Mbc1RamBattery,
+    Mbc2,
+    Mbc2Battery,
+    RomRam,
+    RomRamBattery,
+    Mmm01,
+    Mmm01Ram,
+    Mmm01RamBattery,
+    Mbc3TimerBattery,
+    Mbc3TimerRamBattery,
+    Mbc3,
+    Mbc3Ram,
+    Mbc3RamBattery,
+    Mbc5,
+    Mbc5Ram,
+    Mbc5RamBattery,
+    Mbc5Rumble,
+    Mbc5RumbleRam,
+    Mbc5RumbleRamBattery,
+    Mbc6,
+    Mbc7SensorRumbleRamBattery,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1RamBattery,
+}
+
+impl CartridgeType {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let cartridge_type = match byte {
+            0x00 => Self::RomOnly,
+            0x01 => Self::Mbc1,
+            0x02 => Self::Mbc1Ram,
+            0x03 => Self::Mbc1RamBattery,
+            0x05 => Self::Mbc2,
+            0x06 => Self::Mbc2Battery,
+            0x08 => Self::RomRam,
+            0x09 => Self::RomRamBattery,
+            0x0B => Self::Mmm01,
+            0x0C => Self::Mmm01Ram,
+            0x0D => Self::Mmm01RamBattery,
+            0x0F => Self::Mbc3TimerBattery,
+            0x10 => Self::Mbc3TimerRamBattery,
+            0x11 => Self::Mbc3,
+            0x12 => Self::Mbc3Ram,
+            0x13 => Self::Mbc3RamBattery,
+            0x19 => Self::Mbc5,
+            0x1A => Self::Mbc5Ram,
+            0x1B => Self::Mbc5RamBattery,
+            0x1C => Self::Mbc5Rumble,
+            0x1D => Self::Mbc5RumbleRam,
+            0x1E => Self::Mbc5RumbleRamBattery,
+            0x20 => Self::Mbc6,
+            0x22 => Self::Mbc7SensorRumbleRamBattery,
+            0xFC => Self::PocketCamera,
+            0xFD => Self::Tama5,
+            0xFE => Self::HuC3,
+            0xFF => Self::HuC1RamBattery,
+            _ => return None,
+        };
+        Some(cartridge_type)
+    }
+
+    pub fn mbc_kind(&self) -> MbcKind {
+        match self {
+            Self::RomOnly | Self::RomRam | Self::RomRamBattery => MbcKind::None,
+            Self::Mbc1 | Self::Mbc1Ram | Self::Mbc1RamBattery => MbcKind::Mbc1,
+            Self::Mbc2 | Self::Mbc2Battery => MbcKind::Mbc2,
+            Self::Mmm01 | Self::Mmm01Ram | Self::Mmm01RamBattery => MbcKind::Mmm01,
+            Self::Mbc3TimerBattery
+            | Self::Mbc3TimerRamBattery
+            | Self::Mbc3
+            | Self::Mbc3Ram
+            | Self::Mbc3RamBattery => MbcKind::Mbc3,
+            Self::Mbc5
+            | Self::Mbc5Ram
+            | Self::Mbc5RamBattery
+            | Self::Mbc5Rumble
+            | Self::Mbc5RumbleRam
+            | Self::Mbc5RumbleRamBattery => MbcKind::Mbc5,
+            Self::Mbc6 => MbcKind::Mbc6,
+            Self::Mbc7SensorRumbleRamBattery => MbcKind::Mbc7,
+            Self::PocketCamera => MbcKind::PocketCamera,
+            Self::Tama5 => MbcKind::Tama5,
+            Self::HuC3 => MbcKind::HuC3,
+            Self::HuC1RamBattery => MbcKind::HuC1,
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            Self::Mbc1RamBattery
+                | Self::Mbc2Battery
+                | Self::RomRamBattery
+                | Self::Mmm01RamBattery
+                | Self::Mbc3TimerBattery
+                | Self::Mbc3TimerRamBattery
+                | Self::Mbc3RamBattery
+                | Self::Mbc5RamBattery
+                | Self::Mbc5RumbleRamBattery
+                | Self::Mbc7SensorRumbleRamBattery
+                | Self::HuC1RamBattery
+        )
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(self, Self::Mbc3TimerBattery | Self::Mbc3TimerRamBattery)
+    }
+}
+
+pub(super) const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    nintendo_logo: [u8; 48],
+    title: [u8; 16],
+    new_licensee_code: [u8; 2],
+    sgb_flag: u8,
+    cartridge_type: u8,
+    rom_size: u8,
+    ram_size: u8,
+    destination_code: u8,
+    old_licensee_code: u8,
+    mask_rom_version: u8,
+    header_checksum: u8,
+    global_checksum: [u8; 2],
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, LoadError> {
+        if rom.len() < 0x0150 {
+            return Err(LoadError::TooSmall(rom.len()));
+        }
+        Ok(Self {
+            nintendo_logo: rom[0x0104..=0x0133].try_into().unwrap(),
+            title: rom[0x0134..=0x0143].try_into().unwrap(),
+            new_licensee_code: rom[0x0144..=0x0145].try_into().unwrap(),
+            sgb_flag: rom[0x0146],
+            cartridge_type: rom[0x0147],
+            rom_size: rom[0x0148],
+            ram_size: rom[0x0149],
+            destination_code: rom[0x014A],
+            old_licensee_code: rom[0x014B],
+            mask_rom_version: rom[0x014C],
+            header_checksum: rom[0x014D],
+            global_checksum: rom[0x014E..=0x014F].try_into().unwrap(),
+        })
+    }
+
+    pub fn cartridge_type(&self) -> Option<CartridgeType> {
+        CartridgeType::from_byte(self.cartridge_type)
+    }
+
+    pub fn raw_cartridge_type(&self) -> u8 {
+        self.cartridge_type
+    }
+
+    pub fn title(&self) -> String {
+        self.title
+            .iter()
+            .take_while(|b| **b != 0)
+            .map(|b| *b as char)
+            .collect()
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+
+    // Hardware doesn't check this, it's only useful to validate ROM dumps
+    pub fn verify_global_checksum(&self, rom: &[u8]) -> bool {
+        let sum = rom
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !(0x014E..=0x014F).contains(i))
+            .fold(0u16, |acc, (_, b)| acc.wrapping_add(*b as u16));
+        sum == u16::from_be_bytes(self.global_checksum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn cartridge_type_from_byte() {
+        let rom_only = CartridgeType::from_byte(0x00).unwrap();
+        assert_eq!(rom_only, CartridgeType::RomOnly);
+        assert_eq!(rom_only.mbc_kind(), MbcKind::None);
+        assert!(!rom_only.has_battery());
+
+        let mbc1 = CartridgeType::from_byte(0x03).unwrap();
+        assert_eq!(mbc1, CartridgeType::Mbc1RamBattery);
+        assert_eq!(mbc1.mbc_kind(), MbcKind::Mbc1);
+        assert!(mbc1.has_battery());
+
+        let mbc3 = CartridgeType::from_byte(0x10).unwrap();
+        assert_eq!(mbc3, CartridgeType::Mbc3TimerRamBattery);
+        assert!(mbc3.has_battery());
+        assert!(mbc3.has_timer());
+
+        let mbc5 = CartridgeType::from_byte(0x1C).unwrap();
+        assert_eq!(mbc5, CartridgeType::Mbc5Rumble);
+        assert_eq!(mbc5.mbc_kind(), MbcKind::Mbc5);
+        assert!(!mbc5.has_battery());
+
+        assert_eq!(CartridgeType::from_byte(0x04), None);
+    }
+
+    #[test]
+    fn global_checksum() {
+        let mut rom = fs::read("vendored_test_roms/blargg/instr_timing/instr_timing.gb").unwrap();
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.verify_global_checksum(&rom));
+
+        rom[0x1000] = rom[0x1000].wrapping_add(1);
+        assert!(!header.verify_global_checksum(&rom));
+    }
+}
@@ -1,43 +1,157 @@
+use crate::components::cartridge::header::NINTENDO_LOGO;
 use crate::components::cartridge::Cartridge;
 use crate::{Addressable, KIB};
 
+const MULTICART_SIZE: usize = 1024 * KIB;
+// Each game of a multicart is a 256 KiB segment with its own header
+const MULTICART_SEGMENT: usize = 256 * KIB;
+
 #[derive(Debug, Clone)]
 pub struct Mbc1Cartridge {
     rom: Vec<[u8; 16 * KIB]>,
     rom_bank: u8,
+    // Upper ROM bank bits, or the RAM bank in mode 1
+    bank_2: u8,
+    ram_enabled: bool,
+    advanced_banking_mode: bool,
+    // MBC1M wires bank_2 to bits 4-5 of the bank number instead of 5-6
+    multicart: bool,
 }
 
 impl Mbc1Cartridge {
     pub fn new(rom: Vec<u8>) -> Self {
         let chunks = rom.chunks_exact(16 * KIB);
         assert!(chunks.remainder().is_empty());
+        let multicart = is_multicart(&rom);
+        if multicart {
+            info!("Detected an MBC1 multicart");
+        }
         let rom: Vec<_> = chunks.map(|c| c.try_into().unwrap()).collect();
-        Self { rom, rom_bank: 1 }
+        Self {
+            rom,
+            rom_bank: 1,
+            bank_2: 0,
+            ram_enabled: false,
+            advanced_banking_mode: false,
+            multicart,
+        }
+    }
+
+    fn bank_2_shift(&self) -> usize {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    // Larger bank numbers than the ROM has wrap around. On a multicart the top bit of the 5-bit
+    // register is not connected, but still counts for the bank 0 to 1 translation.
+    fn effective_rom_bank(&self) -> usize {
+        let shift = self.bank_2_shift();
+        let rom_bank = self.rom_bank as usize & ((1 << shift) - 1);
+        ((self.bank_2 as usize) << shift | rom_bank) % self.rom.len()
+    }
+
+    fn effective_rom_bank_0(&self) -> usize {
+        if self.advanced_banking_mode {
+            ((self.bank_2 as usize) << self.bank_2_shift()) % self.rom.len()
+        } else {
+            0
+        }
     }
 }
 
+// There is no header flag for multicarts, so look for a 1 MiB ROM with the logo of more than one
+// game in it
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != MULTICART_SIZE {
+        return false;
+    }
+    let logos = (0..MULTICART_SIZE)
+        .step_by(MULTICART_SEGMENT)
+        .filter(|segment| rom[segment + 0x0104..=segment + 0x0133] == NINTENDO_LOGO)
+        .count();
+    logos > 1
+}
+
 impl Addressable for Mbc1Cartridge {
     fn read(&self, address: u16) -> Option<u8> {
         match address {
-            0x0000..=0x3FFF => Some(self.rom[0][address as usize]),
-            0x4000..=0x7FFF => Some(self.rom[self.rom_bank as usize][(address as usize) - 0x4000]),
+            0x0000..=0x3FFF => Some(self.rom[self.effective_rom_bank_0()][address as usize]),
+            0x4000..=0x7FFF => {
+                Some(self.rom[self.effective_rom_bank()][(address as usize) - 0x4000])
+            }
+            // Cartridge RAM isn't supported, so this is a cartridge without any
+            0xA000..=0xBFFF => Some(0xFF),
             _ => None,
         }
     }
 
     fn write(&mut self, address: u16, byte: u8) -> Option<()> {
         match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = byte & 0x0F == 0x0A;
+                Some(())
+            }
             0x2000..=0x3FFF => {
-                log::trace!("Swapping ROM bank from {} to {}", self.rom_bank, byte);
-                self.rom_bank = byte;
+                // Only the lower 5 bits are used, and bank 0 selects bank 1
+                let bank = match byte & 0b00011111 {
+                    0 => 1,
+                    b => b,
+                };
+                trace!("Swapping ROM bank from {} to {}", self.rom_bank, bank);
+                self.rom_bank = bank;
+                Some(())
+            }
+            0x4000..=0x5FFF => {
+                self.bank_2 = byte & 0b11;
                 Some(())
             }
+            0x6000..=0x7FFF => {
+                self.advanced_banking_mode = byte & 0b1 > 0;
+                Some(())
+            }
+            0xA000..=0xBFFF => Some(()),
             _ => None,
         }
     }
 }
 
-impl Cartridge for Mbc1Cartridge {}
+impl Cartridge for Mbc1Cartridge {
+    fn current_rom_bank(&self) -> u16 {
+        self.effective_rom_bank() as u16
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        if self.advanced_banking_mode {
+            self.bank_2
+        } else {
+            0
+        }
+    }
+
+    fn banking_snapshot(&self) -> Vec<u8> {
+        vec![
+            self.rom_bank,
+            self.bank_2,
+            self.ram_enabled as u8,
+            self.advanced_banking_mode as u8,
+        ]
+    }
+
+    fn restore_banking(&mut self, snapshot: &[u8]) {
+        match *snapshot {
+            [rom_bank, bank_2, ram_enabled, advanced_banking_mode] => {
+                self.rom_bank = rom_bank;
+                self.bank_2 = bank_2;
+                self.ram_enabled = ram_enabled > 0;
+                self.advanced_banking_mode = advanced_banking_mode > 0;
+            }
+            _ => warn!("Invalid MBC1 banking snapshot {:02x?}", snapshot),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -58,4 +172,86 @@ mod tests {
         assert_eq!(&rom.rom[0], &bank_0);
         assert_eq!(&rom.rom[1], &bank_1);
     }
+
+    #[test]
+    fn switch_rom_bank() {
+        let rom: Vec<u8> = (0..8u8).flat_map(|b| [b; 16 * KIB]).collect();
+        let mut rom = Mbc1Cartridge::new(rom);
+        assert_eq!(rom.current_rom_bank(), 1);
+
+        rom.write(0x2000, 3).unwrap();
+        assert_eq!(rom.current_rom_bank(), 3);
+        assert_eq!(rom.read(0x4000), Some(3));
+
+        rom.write(0x2000, 0).unwrap();
+        assert_eq!(rom.current_rom_bank(), 1);
+    }
+
+    #[test]
+    fn upper_rom_bank_bits() {
+        let rom: Vec<u8> = (0..64u8).flat_map(|b| [b; 16 * KIB]).collect();
+        let mut rom = Mbc1Cartridge::new(rom);
+
+        rom.write(0x2000, 0x02).unwrap();
+        rom.write(0x4000, 0x01).unwrap();
+        assert_eq!(rom.current_rom_bank(), 0x22);
+        assert_eq!(rom.read(0x4000), Some(0x22));
+        assert_eq!(rom.read(0x0000), Some(0x00));
+
+        rom.write(0x6000, 0x01).unwrap();
+        assert_eq!(rom.read(0x0000), Some(0x20));
+        assert_eq!(rom.current_ram_bank(), 1);
+    }
+
+    fn multicart_rom(logos: &[usize]) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..64u8).flat_map(|b| [b; 16 * KIB]).collect();
+        for segment in logos {
+            let start = segment * MULTICART_SEGMENT + 0x0104;
+            rom[start..start + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        rom
+    }
+
+    #[test]
+    fn multicart_addressing() {
+        assert!(!Mbc1Cartridge::new(multicart_rom(&[0])).multicart);
+
+        let mut rom = Mbc1Cartridge::new(multicart_rom(&[0, 1]));
+        assert!(rom.multicart);
+
+        // Second game, its bank 2
+        rom.write(0x4000, 0x01).unwrap();
+        rom.write(0x2000, 0x02).unwrap();
+        assert_eq!(rom.current_rom_bank(), 0x12);
+        assert_eq!(rom.read(0x4000), Some(0x12));
+        assert_eq!(rom.read(0x0000), Some(0x00));
+
+        // Bit 4 of the register is ignored, but 0x10 isn't 0 so it doesn't become bank 1
+        rom.write(0x2000, 0x10).unwrap();
+        assert_eq!(rom.current_rom_bank(), 0x10);
+
+        rom.write(0x4000, 0x03).unwrap();
+        rom.write(0x6000, 0x01).unwrap();
+        assert_eq!(rom.read(0x0000), Some(0x30));
+        assert_eq!(rom.read(0x0104), Some(0x30));
+    }
+
+    #[test]
+    fn banking_snapshot() {
+        let rom: Vec<u8> = (0..8u8).flat_map(|b| [b; 16 * KIB]).collect();
+        let mut rom = Mbc1Cartridge::new(rom);
+
+        rom.write(0x0000, 0x0A).unwrap();
+        rom.write(0x2000, 3).unwrap();
+        let snapshot = rom.banking_snapshot();
+
+        rom.write(0x0000, 0x00).unwrap();
+        rom.write(0x2000, 5).unwrap();
+        assert_eq!(rom.current_rom_bank(), 5);
+
+        rom.restore_banking(&snapshot);
+        assert_eq!(rom.current_rom_bank(), 3);
+        assert_eq!(rom.read(0x4000), Some(3));
+        assert!(rom.ram_enabled);
+    }
 }
@@ -1,55 +1,94 @@
+mod flat_ram;
+mod header;
 mod mbc1;
 mod rom_only;
 
 use std::fmt::Debug;
 
+use thiserror::Error;
+
+pub(crate) use crate::components::cartridge::flat_ram::FlatRamCartridge;
+pub use crate::components::cartridge::header::{CartridgeHeader, CartridgeType, MbcKind};
 use crate::components::cartridge::mbc1::Mbc1Cartridge;
 use crate::components::cartridge::rom_only::RomOnlyCartridge;
-use crate::Addressable;
+use crate::{Addressable, KIB};
+
+pub trait Cartridge: Addressable + Debug {
+    fn current_rom_bank(&self) -> u16;
+
+    fn current_ram_bank(&self) -> u8;
 
-pub trait Cartridge: Addressable + Debug {}
+    // Only the bank registers and latches, not the RAM contents
+    fn banking_snapshot(&self) -> Vec<u8>;
+
+    fn restore_banking(&mut self, snapshot: &[u8]);
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BankingState {
+    pub rom_bank: u16,
+    pub ram_bank: u8,
+}
+
+impl BankingState {
+    pub fn of(cartridge: &dyn Cartridge) -> Self {
+        Self {
+            rom_bank: cartridge.current_rom_bank(),
+            ram_bank: cartridge.current_ram_bank(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("Could not read ROM: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("ROM of {0} bytes is too small to contain a header")]
+    TooSmall(usize),
+    #[error("Unused or unsupported cartridge type {0:#04x}")]
+    UnsupportedCartridgeType(u8),
+    #[error("ROM of {0} bytes is not a whole number of 16 KiB banks")]
+    InvalidRomSize(usize),
+    #[error("Boot ROM of {0} bytes is neither 256 (DMG) nor 2304 (CGB) bytes")]
+    InvalidBootRomSize(usize),
+    #[error("Program of {0} bytes at {1:#06x} doesn't fit in the 32 KiB ROM area")]
+    ProgramTooLarge(usize, u16),
+}
 
 pub fn parse_into_cartridge(rom: Vec<u8>) -> Box<dyn Cartridge> {
-    let header = RawCartridgeHeader {
-        nintendo_logo: rom[0x0104..=0x0133].try_into().unwrap(),
-        title: rom[0x0134..=0x0143].try_into().unwrap(),
-        new_licensee_code: rom[0x0144..=0x0145].try_into().unwrap(),
-        sgb_flag: rom[0x0146],
-        cartridge_type: rom[0x0147],
-        rom_size: rom[0x0148],
-        ram_size: rom[0x0149],
-        destination_code: rom[0x014A],
-        old_licensee_code: rom[0x014B],
-        mask_rom_version: rom[0x014C],
-        header_checksum: rom[0x01D],
-        global_checksum: rom[0x014E..=0x014F].try_into().unwrap(),
-    };
-    log::info!("Raw header: {:?}", &header);
-
-    // TODO errors, rom_size/ram_size
-    match header.cartridge_type {
-        0x00 => Box::new(RomOnlyCartridge::new(rom.try_into().unwrap())),
-        0x01 => Box::new(Mbc1Cartridge::new(rom)),
-        _ => panic!(
-            "Unused or unsupported cartridge type {}",
-            header.cartridge_type
-        ),
+    try_parse_into_cartridge(rom).unwrap()
+}
+
+pub fn try_parse_into_cartridge(rom: Vec<u8>) -> Result<Box<dyn Cartridge>, LoadError> {
+    let header = CartridgeHeader::parse(&rom)?;
+    info!("Header: {:?}", &header);
+
+    // TODO rom_size/ram_size
+    match header.cartridge_type() {
+        Some(CartridgeType::RomOnly) => Ok(Box::new(RomOnlyCartridge::new(rom))),
+        Some(CartridgeType::Mbc1) => {
+            if rom.len() % (16 * KIB) != 0 {
+                return Err(LoadError::InvalidRomSize(rom.len()));
+            }
+            Ok(Box::new(Mbc1Cartridge::new(rom)))
+        }
+        _ => Err(LoadError::UnsupportedCartridgeType(
+            header.raw_cartridge_type(),
+        )),
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-struct RawCartridgeHeader {
-    nintendo_logo: [u8; 48],
-    title: [u8; 16],
-    new_licensee_code: [u8; 2],
-    sgb_flag: u8,
-    cartridge_type: u8,
-    rom_size: u8,
-    ram_size: u8,
-    destination_code: u8,
-    old_licensee_code: u8,
-    mask_rom_version: u8,
-    header_checksum: u8,
-    global_checksum: [u8; 2],
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_mbc1() {
+        let mut rom = vec![0; 40 * KIB];
+        rom[0x0147] = 0x01;
+        assert!(matches!(
+            try_parse_into_cartridge(rom),
+            Err(LoadError::InvalidRomSize(0xA000))
+        ));
+    }
 }
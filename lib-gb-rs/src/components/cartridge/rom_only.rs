@@ -1,25 +1,90 @@
 use crate::components::cartridge::Cartridge;
 use crate::{Addressable, KIB};
 
+const ROM_SIZE: usize = 32 * KIB;
+
 #[derive(Debug, Clone)]
 pub struct RomOnlyCartridge {
-    rom: [u8; 32 * KIB],
+    rom: [u8; ROM_SIZE],
 }
 
 impl RomOnlyCartridge {
-    pub fn new(rom: [u8; 32 * KIB]) -> Self {
-        Self { rom }
+    pub fn new(rom: Vec<u8>) -> Self {
+        if rom.len() != ROM_SIZE {
+            warn!(
+                "ROM is {} bytes instead of {}, padding or truncating",
+                rom.len(),
+                ROM_SIZE
+            );
+        }
+        let mut padded = [0xFF; ROM_SIZE];
+        let len = rom.len().min(ROM_SIZE);
+        padded[..len].copy_from_slice(&rom[..len]);
+        Self { rom: padded }
     }
 }
 
 impl Addressable for RomOnlyCartridge {
     fn read(&self, address: u16) -> Option<u8> {
-        self.rom.get(address as usize).copied()
+        match address {
+            // No RAM, the bus floats
+            0xA000..=0xBFFF => Some(0xFF),
+            _ => self.rom.get(address as usize).copied(),
+        }
+    }
+
+    fn write(&mut self, address: u16, _byte: u8) -> Option<()> {
+        match address {
+            0xA000..=0xBFFF => Some(()),
+            _ => None,
+        }
+    }
+}
+
+impl Cartridge for RomOnlyCartridge {
+    fn current_rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn current_ram_bank(&self) -> u8 {
+        0
     }
 
-    fn write(&mut self, _address: u16, _byte: u8) -> Option<()> {
-        None
+    fn banking_snapshot(&self) -> Vec<u8> {
+        Vec::new()
     }
+
+    fn restore_banking(&mut self, _snapshot: &[u8]) {}
 }
 
-impl Cartridge for RomOnlyCartridge {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_short_rom() {
+        let rom = RomOnlyCartridge::new(vec![0x12; 100]);
+
+        assert_eq!(rom.read(0x0063), Some(0x12));
+        assert_eq!(rom.read(0x0064), Some(0xFF));
+        assert_eq!(rom.read(0x7FFF), Some(0xFF));
+    }
+
+    #[test]
+    fn truncate_long_rom() {
+        let rom = RomOnlyCartridge::new(vec![0x12; 64 * KIB]);
+
+        assert_eq!(rom.read(0x7FFF), Some(0x12));
+        assert_eq!(rom.read(0x8000), None);
+    }
+
+    #[test]
+    fn no_ram() {
+        let mut rom = RomOnlyCartridge::new(vec![0x12; 32 * KIB]);
+
+        assert_eq!(rom.write(0xA000, 0x34), Some(()));
+        assert_eq!(rom.read(0xA000), Some(0xFF));
+        assert_eq!(rom.read(0xBFFF), Some(0xFF));
+        assert_eq!(rom.read(0xC000), None);
+    }
+}
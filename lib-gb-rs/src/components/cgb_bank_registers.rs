@@ -0,0 +1,85 @@
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::{Addressable, Model};
+
+// VBK and SVBK. Only the registers themselves for now, VRAM and WRAM are not banked yet.
+#[derive(Debug)]
+pub struct CgbBankRegisters {
+    model: Model,
+    vram_bank: u8,
+    wram_bank: u8,
+}
+
+impl CgbBankRegisters {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            vram_bank: 0,
+            wram_bank: 0,
+        }
+    }
+}
+
+impl Addressable for CgbBankRegisters {
+    fn read(&self, address: u16) -> Option<u8> {
+        match (address, self.model.is_cgb()) {
+            (0xFF4F | 0xFF70, false) => Some(0xFF),
+            (0xFF4F, true) => Some(0b11111110 | self.vram_bank),
+            (0xFF70, true) => Some(0b11111000 | self.wram_bank),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Option<()> {
+        match (address, self.model.is_cgb()) {
+            (0xFF4F | 0xFF70, false) => Some(()),
+            (0xFF4F, true) => {
+                self.vram_bank = value & 0b1;
+                Some(())
+            }
+            (0xFF70, true) => {
+                self.wram_bank = value & 0b111;
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SaveState for CgbBankRegisters {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.vram_bank);
+        w.u8(self.wram_bank);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.vram_bank = r.u8()?;
+        self.wram_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dmg_ignores_writes() {
+        let mut registers = CgbBankRegisters::new(Model::Dmg);
+        assert_eq!(registers.read(0xFF4F), Some(0xFF));
+        registers.write(0xFF4F, 0x01).unwrap();
+        assert_eq!(registers.read(0xFF4F), Some(0xFF));
+        registers.write(0xFF70, 0x03).unwrap();
+        assert_eq!(registers.read(0xFF70), Some(0xFF));
+    }
+
+    #[test]
+    fn cgb_reads_back_bank() {
+        let mut registers = CgbBankRegisters::new(Model::Cgb);
+        registers.write(0xFF4F, 0xFF).unwrap();
+        assert_eq!(registers.read(0xFF4F), Some(0xFF));
+        registers.write(0xFF4F, 0x00).unwrap();
+        assert_eq!(registers.read(0xFF4F), Some(0xFE));
+        registers.write(0xFF70, 0x03).unwrap();
+        assert_eq!(registers.read(0xFF70), Some(0xFB));
+    }
+}
@@ -1,6 +1,9 @@
 use bitflags::bitflags;
 use strum_macros::Display;
 
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::Model;
+
 bitflags! {
     #[derive(Default)]
     pub struct Flags: u8 {
@@ -64,12 +67,13 @@ impl Register16 {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum State {
+pub enum CpuState {
     Running,
     Halted,
+    Stopped,
 }
 
-impl Default for State {
+impl Default for CpuState {
     fn default() -> Self {
         Self::Running
     }
@@ -87,23 +91,35 @@ pub struct Cpu {
     l: u8,
     sp: u16,
     pc: u16,
-    state: State,
+    state: CpuState,
 }
 
 impl Cpu {
     pub fn after_boot_rom() -> Self {
+        Self::after_boot_rom_for(Model::default())
+    }
+
+    // H and C are only set on DMG and MGB if the header checksum is non-zero, which it is for
+    // anything that passes the boot ROM's own check
+    pub fn after_boot_rom_for(model: Model) -> Self {
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::Dmg => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Mgb => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
         Self {
-            a: 0,
-            f: Flags::empty(),
-            b: 0,
-            c: 0,
-            d: 0,
-            e: 0,
-            h: 0,
-            l: 0,
+            a,
+            f: Flags::from_bits_truncate(f),
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
             sp: 0xFFFE,
             pc: 0x0100,
-            state: State::Running,
+            state: CpuState::Running,
         }
     }
 
@@ -148,6 +164,7 @@ impl Cpu {
         match reg {
             Register16::AF => {
                 self.a = high;
+                // The low nibble of F doesn't exist, so e.g. POP AF can't set it
                 self.f = Flags::from_bits_truncate(low);
             }
             Register16::BC => {
@@ -179,11 +196,11 @@ impl Cpu {
         f(&mut self.f)
     }
 
-    pub fn state(&self) -> State {
+    pub fn state(&self) -> CpuState {
         self.state
     }
 
-    pub fn set_state(&mut self, state: State) {
+    pub fn set_state(&mut self, state: CpuState) {
         self.state = state;
     }
 }
@@ -202,3 +219,46 @@ impl std::fmt::Display for Cpu {
         Ok(())
     }
 }
+
+impl SaveState for Cpu {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&[
+            self.a,
+            self.f.bits,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+        ]);
+        w.u16(self.sp);
+        w.u16(self.pc);
+        w.u8(match self.state {
+            CpuState::Running => 0,
+            CpuState::Halted => 1,
+            CpuState::Stopped => 2,
+        });
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let [a, f, b, c, d, e, h, l] = r.array()?;
+        self.a = a;
+        self.f = Flags::from_bits_truncate(f);
+        self.b = b;
+        self.c = c;
+        self.d = d;
+        self.e = e;
+        self.h = h;
+        self.l = l;
+        self.sp = r.u16()?;
+        self.pc = r.u16()?;
+        self.state = match r.u8()? {
+            0 => CpuState::Running,
+            1 => CpuState::Halted,
+            2 => CpuState::Stopped,
+            _ => return Err(StateError::Invalid("CPU state")),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::Addressable;
+
+const DMA: u16 = 0xFF46;
+// From the write to the first byte, in T-cycles
+const START_DELAY: u8 = 8;
+pub const OAM_DMA_LENGTH: u8 = 0xA0;
+
+#[derive(Debug, Copy, Clone)]
+struct Transfer {
+    source: u8,
+    index: u8,
+    phase: u8,
+}
+
+// OAM DMA, one byte per M-cycle. Writes during the start delay of an earlier write queue up behind
+// it, and a running transfer keeps going until the one replacing it starts.
+#[derive(Debug, Default, Clone)]
+pub struct OamDma {
+    register: u8,
+    // Source and remaining delay of up to two writes, oldest first
+    pending: [Option<(u8, u8)>; 2],
+    transfer: Option<Transfer>,
+}
+
+impl OamDma {
+    // Returns the source address and the OAM index to copy it to
+    pub fn tick(&mut self) -> Option<(u16, u8)> {
+        let mut started = false;
+        for (source, delay) in self.pending.iter_mut().flatten() {
+            *delay -= 1;
+            if *delay == 0 {
+                self.transfer = Some(Transfer {
+                    source: *source,
+                    index: 0,
+                    phase: 0,
+                });
+                started = true;
+            }
+        }
+        if started {
+            self.pending = [self.pending[1], None];
+            return None;
+        }
+
+        let transfer = self.transfer.as_mut()?;
+        transfer.phase += 1;
+        if transfer.phase < 4 {
+            return None;
+        }
+        transfer.phase = 0;
+        let index = transfer.index;
+        let address = ((transfer.source as u16) << 8) | index as u16;
+        transfer.index += 1;
+        if transfer.index == OAM_DMA_LENGTH {
+            self.transfer = None;
+        }
+        Some((source_address(address), index))
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    fn start(&mut self, source: u8) {
+        match self.pending {
+            [None, _] => self.pending[0] = Some((source, START_DELAY)),
+            _ => self.pending[1] = Some((source, START_DELAY)),
+        }
+    }
+}
+
+// Sources above WRAM read the echo of it
+fn source_address(address: u16) -> u16 {
+    if address >= 0xE000 {
+        address - 0x2000
+    } else {
+        address
+    }
+}
+
+impl Addressable for OamDma {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            DMA => Some(self.register),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Option<()> {
+        match address {
+            DMA => {
+                self.register = value;
+                self.start(value);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SaveState for OamDma {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.register);
+        for pending in self.pending {
+            match pending {
+                Some((source, delay)) => w.bytes(&[1, source, delay]),
+                None => w.bytes(&[0, 0, 0]),
+            }
+        }
+        match self.transfer {
+            Some(t) => w.bytes(&[1, t.source, t.index, t.phase]),
+            None => w.bytes(&[0, 0, 0, 0]),
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.register = r.u8()?;
+        for pending in &mut self.pending {
+            let [active, source, delay] = r.array()?;
+            *pending = (active == 1).then(|| (source, delay));
+        }
+        let [active, source, index, phase] = r.array()?;
+        self.transfer = (active == 1).then(|| Transfer {
+            source,
+            index,
+            phase,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfer_timing() {
+        let mut dma = OamDma::default();
+        dma.write(DMA, 0xC1).unwrap();
+        assert_eq!(dma.read(DMA), Some(0xC1));
+
+        let mut copies = Vec::new();
+        let mut ticks = 0;
+        while ticks == 0 || dma.is_active() || dma.pending[0].is_some() {
+            ticks += 1;
+            if let Some(copy) = dma.tick() {
+                copies.push((ticks, copy));
+            }
+        }
+
+        assert_eq!(copies.len(), OAM_DMA_LENGTH as usize);
+        assert_eq!(copies[0], (START_DELAY as u16 + 4, (0xC100, 0)));
+        assert_eq!(copies[159], (START_DELAY as u16 + 160 * 4, (0xC19F, 159)));
+    }
+
+    #[test]
+    fn restart_keeps_running_transfer() {
+        let mut dma = OamDma::default();
+        dma.write(DMA, 0xC0).unwrap();
+        for _ in 0..START_DELAY + 4 * 10 {
+            dma.tick();
+        }
+        dma.write(DMA, 0xC1).unwrap();
+        let copies: Vec<_> = (0..START_DELAY + 4).filter_map(|_| dma.tick()).collect();
+        assert!(dma.is_active());
+        assert_eq!(copies, [(0xC00A, 10), (0xC100, 0)]);
+    }
+
+    #[test]
+    fn echo_ram_source() {
+        let mut dma = OamDma::default();
+        dma.write(DMA, 0xFE).unwrap();
+        let first = (0..START_DELAY + 4).find_map(|_| dma.tick());
+        assert_eq!(first, Some((0xDE00, 0)));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::Addressable;
 
 pub struct HighRam {
@@ -31,3 +32,13 @@ impl Addressable for HighRam {
         }
     }
 }
+
+impl SaveState for HighRam {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.fill(&mut self.ram)
+    }
+}
@@ -1,5 +1,6 @@
 use bitflags::bitflags;
 
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::{Addressable, HandleInterruptContext, InterruptContext};
 
 bitflags! {
@@ -35,6 +36,15 @@ pub enum Interrupt {
 }
 
 impl Interrupt {
+    // Highest priority first
+    const PRIORITY: [Interrupt; 5] = [
+        Interrupt::VBlank,
+        Interrupt::LcdStat,
+        Interrupt::Timer,
+        Interrupt::Serial,
+        Interrupt::Joypad,
+    ];
+
     pub fn handler_address(&self) -> u16 {
         match self {
             Interrupt::VBlank => 0x40,
@@ -62,11 +72,28 @@ impl std::fmt::Display for Interrupt {
 pub struct InterruptController {
     interrupt_master_enable: bool,
     interrupt_flag: InterruptFlag,
-    interrupt_enable: InterruptFlag,
+    // All 8 bits of IE are read/write, even though only the low 5 enable anything
+    interrupt_enable: u8,
     ime_scheduled: bool,
 }
 
 impl InterruptController {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn enabled(&self) -> InterruptFlag {
+        InterruptFlag::from_bits_truncate(self.interrupt_enable)
+    }
+
+    // Requested and enabled, in the order they would be serviced, even while IME is off
+    pub fn pending(&self) -> impl Iterator<Item = Interrupt> + '_ {
+        let candidates = self.interrupt_flag.intersection(self.enabled());
+        Interrupt::PRIORITY
+            .into_iter()
+            .filter(move |&interrupt| candidates.contains(interrupt.into()))
+    }
+
     pub fn tick(&mut self) {
         if self.ime_scheduled {
             self.interrupt_master_enable = true;
@@ -79,7 +106,7 @@ impl Addressable for InterruptController {
     fn read(&self, address: u16) -> Option<u8> {
         match address {
             0xFF0F => Some(self.interrupt_flag.bits),
-            0xFFFF => Some(self.interrupt_enable.bits),
+            0xFFFF => Some(self.interrupt_enable),
             _ => None,
         }
     }
@@ -91,7 +118,7 @@ impl Addressable for InterruptController {
                 Some(())
             }
             0xFFFF => {
-                self.interrupt_enable = InterruptFlag::from_bits_truncate(value);
+                self.interrupt_enable = value;
                 Some(())
             }
             _ => None,
@@ -111,32 +138,24 @@ impl HandleInterruptContext for InterruptController {
     }
 
     fn should_start_interrupt_routine(&self) -> bool {
-        self.interrupt_master_enable && self.interrupt_flag.intersects(self.interrupt_enable)
+        self.interrupt_master_enable && self.interrupt_flag.intersects(self.enabled())
     }
 
     fn get_highest_priority_interrupt(&self) -> Option<Interrupt> {
         if !self.interrupt_master_enable {
             None
         } else {
-            let candidates = self.interrupt_flag.intersection(self.interrupt_enable);
-            if candidates.contains(InterruptFlag::VBLANK) {
-                Some(Interrupt::VBlank)
-            } else if candidates.contains(InterruptFlag::LCD_STAT) {
-                Some(Interrupt::LcdStat)
-            } else if candidates.contains(InterruptFlag::TIMER) {
-                Some(Interrupt::Timer)
-            } else if candidates.contains(InterruptFlag::SERIAL) {
-                Some(Interrupt::Serial)
-            } else if candidates.contains(InterruptFlag::JOYPAD) {
-                Some(Interrupt::Joypad)
-            } else {
-                None
-            }
+            self.pending().next()
         }
     }
 
     fn should_cancel_halt(&self) -> bool {
-        self.interrupt_flag.intersects(self.interrupt_enable)
+        self.interrupt_flag.intersects(self.enabled())
+    }
+
+    fn should_cancel_stop(&self) -> bool {
+        // Depends on the joypad lines, not on interrupts
+        false
     }
 
     fn schedule_ime_enable(&mut self) {
@@ -165,6 +184,59 @@ impl std::fmt::Display for InterruptController {
             }
         )?;
         writeln!(f, "interrupt flags: {:?}", self.interrupt_flag)?;
-        writeln!(f, "interrupts enabled: {:?}", self.interrupt_enable)
+        writeln!(f, "interrupts enabled: {:?}", self.enabled())
+    }
+}
+
+impl SaveState for InterruptController {
+    fn save(&self, w: &mut StateWriter) {
+        w.bool(self.interrupt_master_enable);
+        w.u8(self.interrupt_flag.bits);
+        w.u8(self.interrupt_enable);
+        w.bool(self.ime_scheduled);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.interrupt_master_enable = r.bool()?;
+        self.interrupt_flag = InterruptFlag::from_bits_truncate(r.u8()?);
+        self.interrupt_enable = r.u8()?;
+        self.ime_scheduled = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ie_keeps_all_bits() {
+        let mut controller = InterruptController::default();
+        controller.write(0xFFFF, 0xFF);
+        assert_eq!(controller.read(0xFFFF), Some(0xFF));
+        controller.write(0xFFFF, 0x00);
+        assert_eq!(controller.read(0xFFFF), Some(0x00));
+
+        // The upper bits don't enable anything
+        controller.write(0xFFFF, 0xE0);
+        controller.raise_interrupt(Interrupt::Joypad);
+        assert!(!controller.should_cancel_halt());
+    }
+
+    #[test]
+    fn pending_in_priority_order() {
+        let mut controller = InterruptController::default();
+        controller.write(0xFFFF, 0b00000101).unwrap();
+        controller.write(0xFF0F, 0b00001101).unwrap();
+        assert_eq!(
+            controller.pending().collect::<Vec<_>>(),
+            [Interrupt::VBlank, Interrupt::Timer]
+        );
+        assert_eq!(controller.get_highest_priority_interrupt(), None);
+        controller.enable_interrupts();
+        assert_eq!(
+            controller.get_highest_priority_interrupt(),
+            Some(Interrupt::VBlank)
+        );
     }
 }
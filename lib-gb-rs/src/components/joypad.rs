@@ -0,0 +1,209 @@
+use bitflags::bitflags;
+
+use crate::components::interrupt_controller::Interrupt;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::{Addressable, InterruptContext};
+
+bitflags! {
+    // Active low: a cleared bit selects the group
+    struct Select: u8 {
+        const ACTION = 0b00100000;
+        const DIRECTION = 0b00010000;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    fn line(&self) -> u8 {
+        match self {
+            Button::Right | Button::A => 0b0001,
+            Button::Left | Button::B => 0b0010,
+            Button::Up | Button::Select => 0b0100,
+            Button::Down | Button::Start => 0b1000,
+        }
+    }
+
+    fn is_action(&self) -> bool {
+        matches!(self, Button::A | Button::B | Button::Select | Button::Start)
+    }
+
+    fn opposite(&self) -> Option<Button> {
+        match self {
+            Button::Right => Some(Button::Left),
+            Button::Left => Some(Button::Right),
+            Button::Up => Some(Button::Down),
+            Button::Down => Some(Button::Up),
+            _ => None,
+        }
+    }
+}
+
+// Opposing directions can be pressed together on hardware, and some games rely on it
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DpadConflictPolicy {
+    Allow,
+    // Pressing a direction releases the opposite one
+    Forbid,
+}
+
+impl Default for DpadConflictPolicy {
+    fn default() -> Self {
+        Self::Allow
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joypad {
+    select: Select,
+    // Active high, unlike the register
+    directions: u8,
+    actions: u8,
+    dpad_conflict_policy: DpadConflictPolicy,
+}
+
+impl Joypad {
+    pub fn set_dpad_conflict_policy(&mut self, policy: DpadConflictPolicy) {
+        self.dpad_conflict_policy = policy;
+    }
+
+    pub fn set_button<C: InterruptContext>(&mut self, button: Button, pressed: bool, ctx: &mut C) {
+        let before = self.selected_lines();
+        if let (true, DpadConflictPolicy::Forbid, Some(opposite)) =
+            (pressed, self.dpad_conflict_policy, button.opposite())
+        {
+            self.directions &= !opposite.line();
+        }
+        let group = if button.is_action() {
+            &mut self.actions
+        } else {
+            &mut self.directions
+        };
+        if pressed {
+            *group |= button.line();
+        } else {
+            *group &= !button.line();
+        }
+
+        // Interrupt on a high to low transition of any selected line
+        if self.selected_lines() & !before != 0 {
+            ctx.raise_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn any_selected_pressed(&self) -> bool {
+        self.selected_lines() != 0
+    }
+
+    fn selected_lines(&self) -> u8 {
+        let mut lines = 0;
+        if !self.select.contains(Select::DIRECTION) {
+            lines |= self.directions;
+        }
+        if !self.select.contains(Select::ACTION) {
+            lines |= self.actions;
+        }
+        lines
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self {
+            select: Select::all(),
+            directions: 0,
+            actions: 0,
+            dpad_conflict_policy: DpadConflictPolicy::default(),
+        }
+    }
+}
+
+impl Addressable for Joypad {
+    fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0xFF00 => Some(0b11000000 | self.select.bits | (!self.selected_lines() & 0x0F)),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) -> Option<()> {
+        match address {
+            0xFF00 => {
+                self.select = Select::from_bits_truncate(value);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl SaveState for Joypad {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.select.bits);
+        w.u8(self.directions);
+        w.u8(self.actions);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.select = Select::from_bits_truncate(r.u8()?);
+        self.directions = r.u8()?;
+        self.actions = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::interrupt_controller::InterruptController;
+
+    #[test]
+    fn unused_and_unselected_bits() {
+        let mut joypad = Joypad::default();
+        let mut ctx = InterruptController::default();
+        joypad.set_button(Button::A, true, &mut ctx);
+        joypad.set_button(Button::Down, true, &mut ctx);
+
+        // The select bits read back, bits 6 and 7 are always set
+        joypad.write(0xFF00, 0x30).unwrap();
+        assert_eq!(joypad.read(0xFF00), Some(0xFF));
+        joypad.write(0xFF00, 0x00).unwrap();
+        assert_eq!(joypad.read(0xFF00), Some(0xC6));
+        joypad.write(0xFF00, 0x20).unwrap();
+        assert_eq!(joypad.read(0xFF00), Some(0xE7));
+        joypad.write(0xFF00, 0x10).unwrap();
+        assert_eq!(joypad.read(0xFF00), Some(0xDE));
+    }
+
+    #[test]
+    fn dpad_conflict_policy() {
+        let mut joypad = Joypad::default();
+        let mut ctx = InterruptController::default();
+        joypad.write(0xFF00, 0x20).unwrap();
+
+        joypad.set_button(Button::Left, true, &mut ctx);
+        joypad.set_button(Button::Right, true, &mut ctx);
+        assert_eq!(joypad.read(0xFF00), Some(0xEC));
+
+        joypad.set_button(Button::Right, false, &mut ctx);
+        joypad.set_button(Button::Left, false, &mut ctx);
+        joypad.set_dpad_conflict_policy(DpadConflictPolicy::Forbid);
+        joypad.set_button(Button::Left, true, &mut ctx);
+        joypad.set_button(Button::Right, true, &mut ctx);
+        assert_eq!(joypad.read(0xFF00), Some(0xEE));
+
+        // Other directions are unaffected
+        joypad.set_button(Button::Up, true, &mut ctx);
+        assert_eq!(joypad.read(0xFF00), Some(0xEA));
+    }
+}
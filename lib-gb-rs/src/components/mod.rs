@@ -1,7 +1,12 @@
+pub mod apu;
+pub mod boot_rom;
 pub mod cartridge;
+pub mod cgb_bank_registers;
 pub mod cpu;
+pub mod dma;
 pub mod high_ram;
 pub mod interrupt_controller;
+pub mod joypad;
 pub mod ppu;
 pub mod serial;
 pub mod timer;
@@ -1,13 +1,36 @@
 use std::ops::{Index, IndexMut};
 
-use crate::components::ppu::Color;
+use crate::components::ppu::{Color, PixelSource};
 
-#[derive(Debug, Clone)]
-pub struct Line([Color; 160]);
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Line {
+    colors: [Color; Buffer::WIDTH],
+    // Only filled in when pixel events are on
+    sources: Option<[PixelSource; Buffer::WIDTH]>,
+}
+
+impl Line {
+    pub fn sources(&self) -> Option<&[PixelSource; Buffer::WIDTH]> {
+        self.sources.as_ref()
+    }
+
+    pub(super) fn track_sources(&mut self, enabled: bool) {
+        self.sources = enabled.then(|| [PixelSource::Background; Buffer::WIDTH]);
+    }
+
+    pub(super) fn set_source(&mut self, index: usize, source: PixelSource) {
+        if let Some(sources) = &mut self.sources {
+            sources[index] = source;
+        }
+    }
+}
 
 impl Default for Line {
     fn default() -> Self {
-        Self([Color::White; 160])
+        Self {
+            colors: [Color::White; Buffer::WIDTH],
+            sources: None,
+        }
     }
 }
 
@@ -15,34 +38,57 @@ impl Index<usize> for Line {
     type Output = Color;
 
     fn index(&self, index: usize) -> &Self::Output {
-        &self.0[index]
+        &self.colors[index]
     }
 }
 
 impl IndexMut<usize> for Line {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.0[index]
+        &mut self.colors[index]
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct Buffer([Line; 144]);
+pub struct Buffer([Line; Buffer::HEIGHT]);
 
 impl Buffer {
+    pub const WIDTH: usize = 160;
+    pub const HEIGHT: usize = 144;
+
     pub(super) fn boxed() -> Box<Self> {
         Box::new(Self::default())
     }
 
     pub fn height() -> usize {
-        144
+        Self::HEIGHT
     }
 
     pub fn width() -> usize {
-        160
+        Self::WIDTH
+    }
+
+    // Pixels in a frame, the length of `flatten`
+    pub fn len() -> usize {
+        Self::WIDTH * Self::HEIGHT
     }
 
     pub fn flatten(&self) -> impl Iterator<Item = Color> + '_ {
-        self.0.iter().flat_map(|b| b.0)
+        self.0.iter().flat_map(|b| b.colors)
+    }
+
+    // The palette is RGB for white, light grey, dark grey and black
+    #[cfg(feature = "image")]
+    pub fn to_image(&self, palette: [[u8; 3]; 4]) -> image::RgbImage {
+        let pixels = self
+            .flatten()
+            .flat_map(|c| match c {
+                Color::White => palette[0],
+                Color::LightGrey => palette[1],
+                Color::DarkGrey => palette[2],
+                Color::Black => palette[3],
+            })
+            .collect();
+        image::RgbImage::from_raw(Self::width() as u32, Self::height() as u32, pixels).unwrap()
     }
 }
 
@@ -62,6 +108,36 @@ impl IndexMut<usize> for Buffer {
 
 impl Default for Buffer {
     fn default() -> Self {
-        Self([(); 144].map(|_| Line::default()))
+        Self([(); Self::HEIGHT].map(|_| Line::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dimensions() {
+        assert_eq!(Buffer::WIDTH, 160);
+        assert_eq!(Buffer::HEIGHT, 144);
+        assert_eq!(Buffer::default().flatten().count(), Buffer::len());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn to_image() {
+        let mut buffer = Buffer::default();
+        buffer[10][20] = Color::DarkGrey;
+        let palette = [
+            [0xE0, 0xF8, 0xD0],
+            [0x88, 0xC0, 0x70],
+            [0x34, 0x68, 0x56],
+            [0x08, 0x18, 0x20],
+        ];
+
+        let image = buffer.to_image(palette);
+        assert_eq!(image.dimensions(), (160, 144));
+        assert_eq!(image.get_pixel(20, 10).0, [0x34, 0x68, 0x56]);
+        assert_eq!(image.get_pixel(0, 0).0, [0xE0, 0xF8, 0xD0]);
     }
 }
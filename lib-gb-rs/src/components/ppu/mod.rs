@@ -1,12 +1,20 @@
 mod buffer;
+mod sprite;
 
+use std::cell::Cell;
+use std::fmt::Debug;
 use std::mem;
 
 use bitflags::bitflags;
 pub use buffer::{Buffer, Line};
+use sprite::{Sprite, SpriteFlags};
 
 use crate::components::interrupt_controller::Interrupt;
-use crate::{Addressable, EventContext, ExecutionEvent, InterruptContext};
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
+use crate::{
+    Addressable, EventContext, ExecutionEvent, HexByte, HexWord, InterruptContext, Model,
+    CYCLES_PER_FRAME,
+};
 
 bitflags! {
     struct LCDC: u8 {
@@ -30,7 +38,25 @@ bitflags! {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+pub trait FrameSink: Debug {
+    fn frame(&mut self, buffer: &Buffer);
+}
+
+// Called with every completed visible line, before the frame is done
+pub trait ScanlineSink: Debug {
+    fn line(&mut self, ly: u8, line: &Line);
+}
+
+// Which layer a pixel came from, objects by OAM index
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PixelSource {
+    Background,
+    // Not produced until the window is rendered
+    Window,
+    Object(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ColorId {
     Zero,
     One,
@@ -50,7 +76,7 @@ impl ColorId {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Color {
     White,
     LightGrey,
@@ -97,6 +123,20 @@ impl TileData {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PaletteKind {
+    Bg,
+    Obj0,
+    Obj1,
+}
+
+// The map at 0x9800 and the one at 0x9C00
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TileMap {
+    Low,
+    High,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
     HBlank0,
@@ -116,13 +156,112 @@ impl Mode {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PpuRegisters {
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub mode: Mode,
+}
+
+// Decoded LCDC and STAT bits
+impl PpuRegisters {
+    fn lcdc(&self) -> LCDC {
+        LCDC::from_bits_truncate(self.lcdc)
+    }
+
+    fn stat(&self) -> Stat {
+        Stat::from_bits_truncate(self.stat)
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc().contains(LCDC::LCD_PPU_ENABLE)
+    }
+
+    pub fn window_tile_map(&self) -> u16 {
+        if self.lcdc().contains(LCDC::WINDOW_TILE_MAP_AREA) {
+            0x9C00
+        } else {
+            0x9800
+        }
+    }
+
+    pub fn window_enabled(&self) -> bool {
+        self.lcdc().contains(LCDC::WINDOW_ENABLE)
+    }
+
+    // Tiles from 0x8800 are addressed with signed indices from 0x9000
+    pub fn bg_window_tile_data(&self) -> u16 {
+        if self.lcdc().contains(LCDC::BG_WINDOW_TILE_DATA_AREA) {
+            0x8000
+        } else {
+            0x8800
+        }
+    }
+
+    pub fn bg_tile_map(&self) -> u16 {
+        if self.lcdc().contains(LCDC::BG_TILE_MAP_AREA) {
+            0x9C00
+        } else {
+            0x9800
+        }
+    }
+
+    // Object height in pixels
+    pub fn obj_size(&self) -> u8 {
+        if self.lcdc().contains(LCDC::OBJ_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
+    pub fn obj_enabled(&self) -> bool {
+        self.lcdc().contains(LCDC::OBJ_ENABLE)
+    }
+
+    pub fn bg_window_enabled(&self) -> bool {
+        self.lcdc().contains(LCDC::BG_WINDOW_ENABLE)
+    }
+
+    pub fn lyc_interrupt_enabled(&self) -> bool {
+        self.stat().contains(Stat::LYC_IS_LY_INTERRUPT)
+    }
+
+    pub fn oam_interrupt_enabled(&self) -> bool {
+        self.stat().contains(Stat::OAM_INTERRUPT)
+    }
+
+    pub fn vblank_interrupt_enabled(&self) -> bool {
+        self.stat().contains(Stat::VBLANK_INTERRUPT)
+    }
+
+    pub fn hblank_interrupt_enabled(&self) -> bool {
+        self.stat().contains(Stat::HBLANK_INTERRUPT)
+    }
+
+    pub fn lyc_is_ly(&self) -> bool {
+        self.stat & 0b00000100 > 0
+    }
+}
+
 #[derive(Debug)]
 pub struct Ppu {
+    model: Model,
     tile_data_1: [u8; 0x800],
     tile_data_2: [u8; 0x800],
     tile_data_3: [u8; 0x800],
     tile_map_1: [u8; 0x400],
     tile_map_2: [u8; 0x400],
+    oam: [u8; 0xA0],
     x_clock: u16,
     x_pixel: u8,
     ly: u8,
@@ -130,15 +269,70 @@ pub struct Ppu {
     lcdc: LCDC,
     scx: u8,
     scy: u8,
+    wy: u8,
+    wx: u8,
     bg_palette: u8,
+    obj_palette_0: u8,
+    obj_palette_1: u8,
+    sprite_limit: Option<u8>,
+    line_sprites: Vec<Sprite>,
+    // Sprites were selected with this height, even if LCDC changes during the line
+    line_sprite_height: u8,
     frame_buffer: Box<Buffer>,
+    // With double buffering, the last completed frame while the next one is drawn
+    back_buffer: Option<Box<Buffer>>,
     lyc: u8,
     stat: Stat,
     lyc_is_ly: bool,
     previous_stat_interrupt: bool,
+    frame_skip: u32,
+    frames_skipped: u32,
+    skip_current_frame: bool,
+    first_line_after_enable: bool,
+    fine_scroll_discard: u8,
+    // CGB only, bit 0 set means DMG-style priority by x coordinate
+    object_priority_mode: u8,
+    frame_sink: Option<Box<dyn FrameSink>>,
+    scanline_sink: Option<Box<dyn ScanlineSink>>,
+    pixel_events: bool,
+    frame_events: bool,
+    vram_events: bool,
+    frame_buffer_borrowed: Cell<bool>,
+    // Set at every VBlank until taken. The pixels are only copied once a frontend has polled.
+    frame_completed: bool,
+    completed_frame: Option<Box<Buffer>>,
+    frame_polled: Cell<bool>,
+    render_current_frame: bool,
+    // Since the last VBlank start, None until the LCD has been on through one
+    frame_cycles: Option<u64>,
+    last_frame_cycles: u64,
 }
 
 impl Ppu {
+    pub fn new(model: Model) -> Self {
+        Self {
+            model,
+            ..Self::default()
+        }
+    }
+
+    // Back to the state after boot, keeping the sinks and rendering configuration
+    pub fn reset(&mut self) {
+        *self = Self {
+            model: self.model,
+            sprite_limit: self.sprite_limit,
+            frame_skip: self.frame_skip,
+            frame_sink: self.frame_sink.take(),
+            scanline_sink: self.scanline_sink.take(),
+            back_buffer: self.back_buffer.take(),
+            pixel_events: self.pixel_events,
+            frame_events: self.frame_events,
+            vram_events: self.vram_events,
+            frame_polled: self.frame_polled.clone(),
+            ..Self::default()
+        };
+    }
+
     /*
     144 visible scanlines + 10 vblank, 456 dots per line
     State move logic:
@@ -150,7 +344,7 @@ impl Ppu {
     Mode 1: 456 dots
 
     0<=y<144: mode 230
-    144<=y<154: mode 1
+    144<=y<154: mode 1, with LY already 0 for most of line 153
 
     0<=x_clock<80: mode 2
     0<=x_pixel<144: mode 3
@@ -162,19 +356,44 @@ impl Ppu {
         if !self.lcdc.contains(LCDC::LCD_PPU_ENABLE) {
             return;
         }
+        if let Some(cycles) = &mut self.frame_cycles {
+            *cycles += 1;
+        }
         match self.mode {
             Mode::HBlank0 => {
                 self.x_clock += 1;
-                if self.x_clock == 456 {
+                if self.first_line_after_enable && self.x_clock == 80 {
+                    self.first_line_after_enable = false;
+                    self.mode = Mode::LCDOn3;
+                    self.x_pixel = 0;
+                    self.fine_scroll_discard = self.scx % 8;
+                    event_ctx.push_event(ExecutionEvent::PpuModeSwitch {
+                        mode: self.mode,
+                        x: self.x_clock,
+                        y: self.ly,
+                    })
+                } else if self.x_clock == 456 {
                     self.x_clock = 0;
                     self.ly += 1;
                     if self.ly == 144 {
                         self.mode = Mode::VBlank1;
                         ctx.raise_interrupt(Interrupt::VBlank);
-                        event_ctx.push_event(ExecutionEvent::FrameReady(mem::replace(
-                            &mut self.frame_buffer,
-                            Buffer::boxed(),
-                        )));
+                        self.last_frame_cycles = self.frame_cycles.replace(0).unwrap_or(0);
+                        debug_assert!(
+                            self.last_frame_cycles == 0
+                                || self.last_frame_cycles == CYCLES_PER_FRAME,
+                            "Frame took {} cycles",
+                            self.last_frame_cycles
+                        );
+                        if self.skip_current_frame {
+                            event_ctx.push_event(ExecutionEvent::FrameSkipped);
+                        } else if self.render_current_frame {
+                            self.deliver_frame(event_ctx);
+                        } else {
+                            // Still a VBlank for frontends that poll, just without pixels
+                            self.frame_completed = true;
+                        }
+                        self.select_next_frame_skip();
                         event_ctx.push_event(ExecutionEvent::PpuModeSwitch {
                             mode: self.mode,
                             x: self.x_clock,
@@ -192,26 +411,33 @@ impl Ppu {
             }
             Mode::VBlank1 => {
                 self.x_clock += 1;
-                if self.x_clock == 456 {
-                    self.ly += 1;
+                if self.ly == 153 && self.x_clock == 4 {
+                    // LY reads 153 only for the first M-cycle of the last line, then already 0
+                    self.ly = 0;
+                } else if self.x_clock == 456 {
                     self.x_clock = 0;
-                    if self.ly == 154 {
-                        self.ly = 0;
+                    if self.ly == 0 {
                         self.mode = Mode::OAMSearch2;
+                        self.render_current_frame = self.pixels_wanted();
+                        self.clear_frame_buffer();
                         event_ctx.push_event(ExecutionEvent::PpuModeSwitch {
                             mode: self.mode,
                             x: self.x_clock,
                             y: self.ly,
                         })
+                    } else {
+                        self.ly += 1;
                     }
                 }
             }
             Mode::OAMSearch2 => {
-                // Pretend nothing happens here
+                // Do the whole scan at the end instead of one entry every 2 dots
                 self.x_clock += 1;
                 if self.x_clock == 80 {
+                    self.oam_scan();
                     self.mode = Mode::LCDOn3;
                     self.x_pixel = 0;
+                    self.fine_scroll_discard = self.scx % 8;
                     event_ctx.push_event(ExecutionEvent::PpuModeSwitch {
                         mode: self.mode,
                         x: self.x_clock,
@@ -219,21 +445,58 @@ impl Ppu {
                     })
                 }
             }
+            Mode::LCDOn3 if self.fine_scroll_discard > 0 => {
+                // The pixels of the first tile left of SCX are fetched and dropped
+                self.fine_scroll_discard -= 1;
+                self.x_clock += 1;
+            }
             Mode::LCDOn3 => {
                 // Pretend 1 cycle == 1 pixel
-                let x = self.x_pixel.wrapping_add(self.scx);
-                let y = self.ly.wrapping_add(self.scy);
-                let color_id = self.get_current_pixel_color_id(x, y);
-                let color = Color::from_color_id(color_id, self.bg_palette);
-                self.frame_buffer[self.ly as usize][self.x_pixel as usize] = color;
-                event_ctx.push_event(ExecutionEvent::PpuPixelPushed(
-                    self.x_pixel,
-                    self.ly,
-                    color_id,
-                ));
+                if self.render_current_frame && !self.skip_current_frame {
+                    let x = self.x_pixel.wrapping_add(self.scx);
+                    let y = self.ly.wrapping_add(self.scy);
+                    let bg_blank = self.bg_window_blank();
+                    let bg_color_id = if bg_blank {
+                        ColorId::Zero
+                    } else {
+                        self.get_current_pixel_color_id(x, y)
+                    };
+                    let (color_id, color, source) = match self.get_current_sprite_pixel(bg_color_id)
+                    {
+                        Some((color_id, palette, index)) => (
+                            color_id,
+                            Color::from_color_id(color_id, palette),
+                            PixelSource::Object(index),
+                        ),
+                        None if bg_blank => (ColorId::Zero, Color::White, PixelSource::Background),
+                        None => (
+                            bg_color_id,
+                            Color::from_color_id(bg_color_id, self.bg_palette),
+                            PixelSource::Background,
+                        ),
+                    };
+                    let line = &mut self.frame_buffer[self.ly as usize];
+                    if self.x_pixel == 0 {
+                        line.track_sources(self.pixel_events);
+                    }
+                    line[self.x_pixel as usize] = color;
+                    line.set_source(self.x_pixel as usize, source);
+                    if self.pixel_events {
+                        event_ctx.push_event(ExecutionEvent::PpuPixelPushed(
+                            self.x_pixel,
+                            self.ly,
+                            color_id,
+                        ));
+                    }
+                }
                 self.x_clock += 1;
                 self.x_pixel += 1;
                 if self.x_pixel == 160 {
+                    if self.render_current_frame && !self.skip_current_frame {
+                        if let Some(sink) = &mut self.scanline_sink {
+                            sink.line(self.ly, &self.frame_buffer[self.ly as usize]);
+                        }
+                    }
                     self.mode = Mode::HBlank0;
                     event_ctx.push_event(ExecutionEvent::PpuModeSwitch {
                         mode: self.mode,
@@ -244,23 +507,323 @@ impl Ppu {
             }
         }
         self.lyc_is_ly = self.ly == self.lyc;
-        let mut stat = false;
-        if self.stat.contains(Stat::LYC_IS_LY_INTERRUPT) {
-            stat |= self.lyc_is_ly;
+        let stat = self.stat_line();
+        if !self.previous_stat_interrupt && stat {
+            ctx.raise_interrupt(Interrupt::LcdStat)
         }
-        if self.stat.contains(Stat::VBLANK_INTERRUPT) {
-            stat |= self.mode == Mode::VBlank1;
+        self.previous_stat_interrupt = stat;
+    }
+
+    /*
+    All enabled STAT sources are ORed into a single line and only its rising edge requests an
+    interrupt. A source becoming true while another still holds the line high is "blocked", e.g.
+    LYC matching on the same dot mode 2 starts, or mode 2 of the line after an LYC match.
+     */
+    fn stat_line(&self) -> bool {
+        (self.stat.contains(Stat::LYC_IS_LY_INTERRUPT) && self.lyc_is_ly)
+            || (self.stat.contains(Stat::VBLANK_INTERRUPT) && self.mode == Mode::VBlank1)
+            || (self.stat.contains(Stat::OAM_INTERRUPT) && self.mode == Mode::OAMSearch2)
+            || (self.stat.contains(Stat::HBLANK_INTERRUPT) && self.mode == Mode::HBlank0)
+    }
+
+    pub fn registers(&self) -> PpuRegisters {
+        PpuRegisters {
+            lcdc: self.lcdc.bits,
+            stat: self.read_stat(),
+            scy: self.scy,
+            scx: self.scx,
+            ly: self.ly,
+            lyc: self.lyc,
+            bgp: self.bg_palette,
+            obp0: self.obj_palette_0,
+            obp1: self.obj_palette_1,
+            wy: self.wy,
+            wx: self.wx,
+            mode: self.mode,
         }
-        if self.stat.contains(Stat::OAM_INTERRUPT) {
-            stat |= self.mode == Mode::OAMSearch2;
+    }
+
+    // Indexed [row][column], regardless of VRAM being blocked during pixel transfer
+    pub fn tile_map(&self, which: TileMap) -> [[u8; 32]; 32] {
+        let map = match which {
+            TileMap::Low => &self.tile_map_1,
+            TileMap::High => &self.tile_map_2,
+        };
+        let mut rows = [[0; 32]; 32];
+        for (row, indices) in rows.iter_mut().zip(map.chunks_exact(32)) {
+            row.copy_from_slice(indices);
         }
-        if self.stat.contains(Stat::HBLANK_INTERRUPT) {
-            stat |= self.mode == Mode::HBlank0;
+        rows
+    }
+
+    fn read_stat(&self) -> u8 {
+        let mut stat = self.stat.bits;
+        if self.lyc_is_ly {
+            stat |= 0b00000100;
         }
-        if !self.previous_stat_interrupt && stat {
-            ctx.raise_interrupt(Interrupt::LcdStat)
+        stat | self.mode.bits()
+    }
+
+    // Cycles between the last two VBlank starts, 0 unless the LCD was on for all of them
+    pub fn last_frame_cycles(&self) -> u64 {
+        self.last_frame_cycles
+    }
+
+    pub fn set_sprite_limit(&mut self, sprite_limit: Option<u8>) {
+        self.sprite_limit = sprite_limit;
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc.contains(LCDC::OBJ_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn oam_scan(&mut self) {
+        let height = self.sprite_height();
+        let limit = self.sprite_limit.map_or(usize::MAX, |l| l as usize);
+        let ly = self.ly;
+        self.line_sprite_height = height;
+        self.line_sprites.clear();
+        self.line_sprites.extend(
+            self.oam
+                .chunks_exact(4)
+                .enumerate()
+                .map(|(i, entry)| Sprite::from_oam(i, entry))
+                .filter(|s| s.is_on_line(ly, height))
+                .take(limit),
+        );
+    }
+
+    fn priority_by_x(&self) -> bool {
+        !self.model.is_cgb() || self.object_priority_mode & 0b1 > 0
+    }
+
+    // LCDC bit 0 blanks the background and window on DMG
+    fn bg_window_blank(&self) -> bool {
+        !self.model.is_cgb() && !self.lcdc.contains(LCDC::BG_WINDOW_ENABLE)
+    }
+
+    // On CGB it is a master priority bit instead, the background always renders
+    fn objects_always_on_top(&self) -> bool {
+        self.model.is_cgb() && !self.lcdc.contains(LCDC::BG_WINDOW_ENABLE)
+    }
+
+    // The first opaque sprite pixel in priority order: lowest x first then OAM order, or only OAM
+    // order on CGB
+    fn get_current_sprite_pixel(&self, bg_color_id: ColorId) -> Option<(ColorId, u8, u8)> {
+        if !self.lcdc.contains(LCDC::OBJ_ENABLE) {
+            return None;
+        }
+        let mut candidates = self
+            .line_sprites
+            .iter()
+            .filter(|s| s.covers(self.x_pixel))
+            .map(|s| (s, self.get_sprite_pixel_color_id(s)))
+            .filter(|(_, c)| *c != ColorId::Zero);
+        let (sprite, color_id) = if self.priority_by_x() {
+            candidates.min_by_key(|(s, _)| s.x)?
+        } else {
+            candidates.next()?
+        };
+
+        if sprite.flags.contains(SpriteFlags::BG_WINDOW_OVER_OBJ)
+            && bg_color_id != ColorId::Zero
+            && !self.objects_always_on_top()
+        {
+            return None;
+        }
+        let palette = if sprite.flags.contains(SpriteFlags::PALETTE) {
+            self.obj_palette_1
+        } else {
+            self.obj_palette_0
+        };
+        Some((color_id, palette, sprite.index))
+    }
+
+    fn get_sprite_pixel_color_id(&self, sprite: &Sprite) -> ColorId {
+        let height = self.line_sprite_height;
+        let mut line = self.ly + 16 - sprite.y;
+        if sprite.flags.contains(SpriteFlags::Y_FLIP) {
+            line = height - 1 - line;
+        }
+        let mut column = self.x_pixel + 8 - sprite.x;
+        if sprite.flags.contains(SpriteFlags::X_FLIP) {
+            column = 7 - column;
+        }
+        let tile = if height == 16 {
+            (sprite.tile & 0xFE) + line / 8
+        } else {
+            sprite.tile
+        };
+
+        self.read_tile_data_obj(tile).index(column, line % 8)
+    }
+
+    pub fn write_with_events<E: EventContext>(
+        &mut self,
+        address: u16,
+        value: u8,
+        event_ctx: &mut E,
+    ) -> Option<()> {
+        let palette = match address {
+            0xFF47 => Some(PaletteKind::Bg),
+            0xFF48 => Some(PaletteKind::Obj0),
+            0xFF49 => Some(PaletteKind::Obj1),
+            _ => None,
+        };
+        if let Some(which) = palette {
+            event_ctx.push_event(ExecutionEvent::PaletteChanged { which, value });
+        }
+        // Only writes that land, not those blocked during pixel transfer
+        if self.vram_events && (0x8000..=0x9FFF).contains(&address) && self.mode != Mode::LCDOn3 {
+            event_ctx.push_event(ExecutionEvent::VramWritten {
+                address: HexWord(address),
+                value: HexByte(value),
+            });
+        }
+        let disables_lcd = address == 0xFF40
+            && self.lcdc.contains(LCDC::LCD_PPU_ENABLE)
+            && !LCDC::from_bits_truncate(value).contains(LCDC::LCD_PPU_ENABLE);
+        self.write(address, value)?;
+        // A disabled LCD shows a blank screen, so deliver a white frame to clear the display
+        if disables_lcd {
+            *self.frame_buffer = Buffer::default();
+            self.deliver_frame(event_ctx);
+        }
+        Some(())
+    }
+
+    fn deliver_frame<E: EventContext>(&mut self, event_ctx: &mut E) {
+        if let Some(sink) = &mut self.frame_sink {
+            sink.frame(&self.frame_buffer);
+        }
+        self.frame_completed = true;
+        if self.frame_polled.get() {
+            match &mut self.completed_frame {
+                Some(completed) => completed.clone_from(&self.frame_buffer),
+                None => self.completed_frame = Some(self.frame_buffer.clone()),
+            }
+        }
+        if let Some(back_buffer) = &mut self.back_buffer {
+            mem::swap(&mut self.frame_buffer, back_buffer);
+            // The event gets a copy, the back buffer stays readable through frame_buffer
+            if self.frame_events {
+                event_ctx.push_event(ExecutionEvent::FrameReady(back_buffer.clone()));
+            }
+        } else if self.frame_events {
+            event_ctx.push_event(ExecutionEvent::FrameReady(mem::replace(
+                &mut self.frame_buffer,
+                Buffer::boxed(),
+            )));
+        }
+    }
+
+    fn disable_lcd(&mut self) {
+        self.ly = 0;
+        self.x_clock = 0;
+        self.x_pixel = 0;
+        self.mode = Mode::HBlank0;
+        self.first_line_after_enable = false;
+        // The line is only sampled while the LCD is on, so a source already true when it comes
+        // back on still counts as an edge
+        self.previous_stat_interrupt = false;
+    }
+
+    /*
+    The first line after enabling is special: there is no OAM search, mode 0 is reported instead,
+    and the line is 4 dots shorter.
+     */
+    fn enable_lcd(&mut self) {
+        self.ly = 0;
+        self.x_clock = 4;
+        self.x_pixel = 0;
+        self.mode = Mode::HBlank0;
+        self.first_line_after_enable = true;
+        self.frame_cycles = None;
+        self.line_sprites.clear();
+        self.render_current_frame = self.pixels_wanted();
+        self.clear_frame_buffer();
+    }
+
+    // So lines a frame doesn't get to, e.g. because the LCD is turned off, show blank instead of
+    // the previous frame. Blank is white, like color 0 with the boot palette.
+    fn clear_frame_buffer(&mut self) {
+        if self.render_current_frame && !self.skip_current_frame {
+            *self.frame_buffer = Buffer::default();
+        }
+    }
+
+    pub fn set_frame_sink(&mut self, frame_sink: Option<Box<dyn FrameSink>>) {
+        self.frame_sink = frame_sink;
+    }
+
+    pub fn set_scanline_sink(&mut self, scanline_sink: Option<Box<dyn ScanlineSink>>) {
+        self.scanline_sink = scanline_sink;
+    }
+
+    pub fn set_pixel_events(&mut self, enabled: bool) {
+        self.pixel_events = enabled;
+    }
+
+    pub fn set_frame_events(&mut self, enabled: bool) {
+        self.frame_events = enabled;
+    }
+
+    pub fn set_vram_events(&mut self, enabled: bool) {
+        self.vram_events = enabled;
+    }
+
+    pub fn frame_buffer(&self) -> &Buffer {
+        self.frame_buffer_borrowed.set(true);
+        self.back_buffer.as_deref().unwrap_or(&self.frame_buffer)
+    }
+
+    pub fn set_double_buffering(&mut self, enabled: bool) {
+        self.back_buffer = enabled.then(Buffer::boxed);
+    }
+
+    pub fn frame_ready(&self) -> bool {
+        self.frame_polled.set(true);
+        self.frame_completed
+    }
+
+    // A frame completed before the first poll comes back blank, its pixels weren't kept
+    pub fn take_frame(&mut self) -> Option<Buffer> {
+        self.frame_polled.set(true);
+        if !mem::take(&mut self.frame_completed) {
+            return None;
+        }
+        let frame = self.completed_frame.take().map(|frame| *frame);
+        Some(frame.unwrap_or_default())
+    }
+
+    // Decided at the start of a frame, nothing changes timing-wise if no one looks at the pixels
+    fn pixels_wanted(&self) -> bool {
+        let borrowed = self.frame_buffer_borrowed.replace(false);
+        borrowed
+            || self.frame_polled.get()
+            || self.frame_sink.is_some()
+            || self.scanline_sink.is_some()
+            || self.pixel_events
+            || self.frame_events
+    }
+
+    pub fn set_frame_skip(&mut self, frame_skip: u32) {
+        self.frame_skip = frame_skip;
+        self.frames_skipped = 0;
+    }
+
+    // Render one frame, then skip `frame_skip` frames
+    fn select_next_frame_skip(&mut self) {
+        self.skip_current_frame = self.frames_skipped < self.frame_skip;
+        if self.skip_current_frame {
+            self.frames_skipped += 1;
+        } else {
+            self.frames_skipped = 0;
         }
-        self.previous_stat_interrupt = stat;
     }
 
     /*
@@ -308,15 +871,57 @@ impl Ppu {
 
     fn write_vram(&mut self, address: u16, value: u8) {
         if self.mode != Mode::LCDOn3 {
-            let a = address as usize;
-            match address {
-                0x8000..=0x87FF => self.tile_data_1[a - 0x8000] = value,
-                0x8800..=0x8FFF => self.tile_data_2[a - 0x8800] = value,
-                0x9000..=0x97FF => self.tile_data_3[a - 0x9000] = value,
-                0x9800..=0x9BFF => self.tile_map_1[a - 0x9800] = value,
-                0x9C00..=0x9FFF => self.tile_map_2[a - 0x9C00] = value,
-                _ => unreachable!(),
-            }
+            self.store_vram(address, value);
+        }
+    }
+
+    fn store_vram(&mut self, address: u16, value: u8) {
+        let a = address as usize;
+        match address {
+            0x8000..=0x87FF => self.tile_data_1[a - 0x8000] = value,
+            0x8800..=0x8FFF => self.tile_data_2[a - 0x8800] = value,
+            0x9000..=0x97FF => self.tile_data_3[a - 0x9000] = value,
+            0x9800..=0x9BFF => self.tile_map_1[a - 0x9800] = value,
+            0x9C00..=0x9FFF => self.tile_map_2[a - 0x9C00] = value,
+            _ => unreachable!(),
+        }
+    }
+
+    // Not a CPU access, so not blocked in mode 3
+    pub fn load_vram(&mut self, offset: u16, data: &[u8]) {
+        assert!(
+            offset as usize + data.len() <= 0x2000,
+            "VRAM load out of range"
+        );
+        for (address, value) in (0x8000 + offset..).zip(data) {
+            self.store_vram(address, *value);
+        }
+    }
+
+    fn read_oam(&self, address: u16) -> u8 {
+        if self.mode == Mode::OAMSearch2 || self.mode == Mode::LCDOn3 {
+            0xFF
+        } else {
+            self.oam[(address - 0xFE00) as usize]
+        }
+    }
+
+    pub fn write_oam_dma(&mut self, index: u8, value: u8) {
+        self.oam[index as usize] = value;
+    }
+
+    fn write_oam(&mut self, address: u16, value: u8) {
+        if self.mode != Mode::OAMSearch2 && self.mode != Mode::LCDOn3 {
+            self.oam[(address - 0xFE00) as usize] = value;
+        }
+    }
+
+    // Objects always use the unsigned addressing
+    fn read_tile_data_obj(&self, offset: u8) -> TileData {
+        let o = offset as usize;
+        match offset {
+            0..=127 => Self::read_tile_data_at_offset(&self.tile_data_1, o),
+            128..=255 => Self::read_tile_data_at_offset(&self.tile_data_2, o - 128),
         }
     }
 
@@ -355,21 +960,20 @@ impl Addressable for Ppu {
     fn read(&self, address: u16) -> Option<u8> {
         match address {
             0x8000..=0x9FFF => Some(self.read_vram(address)),
+            0xFE00..=0xFE9F => Some(self.read_oam(address)),
             0xFF40 => Some(self.lcdc.bits),
-            0xFF41 => {
-                let mut stat = self.stat.bits;
-                if self.lyc_is_ly {
-                    stat |= 0b00000100;
-                }
-                stat |= self.mode.bits();
-
-                Some(stat)
-            }
+            0xFF41 => Some(self.read_stat()),
             0xFF42 => Some(self.scy),
             0xFF43 => Some(self.scx),
             0xFF44 => Some(self.ly),
             0xFF45 => Some(self.lyc),
             0xFF47 => Some(self.bg_palette),
+            0xFF48 => Some(self.obj_palette_0),
+            0xFF49 => Some(self.obj_palette_1),
+            0xFF4A => Some(self.wy),
+            0xFF4B => Some(self.wx),
+            0xFF6C if self.model.is_cgb() => Some(0b11111110 | self.object_priority_mode),
+            0xFF6C => Some(0xFF),
             _ => None,
         }
     }
@@ -380,8 +984,21 @@ impl Addressable for Ppu {
                 self.write_vram(address, value);
                 Some(())
             }
+            0xFE00..=0xFE9F => {
+                self.write_oam(address, value);
+                Some(())
+            }
             0xFF40 => {
-                self.lcdc = LCDC::from_bits_truncate(value);
+                let lcdc = LCDC::from_bits_truncate(value);
+                match (
+                    self.lcdc.contains(LCDC::LCD_PPU_ENABLE),
+                    lcdc.contains(LCDC::LCD_PPU_ENABLE),
+                ) {
+                    (true, false) => self.disable_lcd(),
+                    (false, true) => self.enable_lcd(),
+                    _ => {}
+                }
+                self.lcdc = lcdc;
                 Some(())
             }
             0xFF41 => {
@@ -404,32 +1021,159 @@ impl Addressable for Ppu {
                 self.bg_palette = value;
                 Some(())
             }
+            0xFF48 => {
+                self.obj_palette_0 = value;
+                Some(())
+            }
+            0xFF49 => {
+                self.obj_palette_1 = value;
+                Some(())
+            }
+            0xFF4A => {
+                self.wy = value;
+                Some(())
+            }
+            0xFF4B => {
+                self.wx = value;
+                Some(())
+            }
+            0xFF6C => {
+                if self.model.is_cgb() {
+                    self.object_priority_mode = value & 0b1;
+                }
+                Some(())
+            }
             _ => None,
         }
     }
 }
 
+// Only the hardware state, not the frame buffer or frontend configuration
+impl SaveState for Ppu {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.tile_data_1);
+        w.bytes(&self.tile_data_2);
+        w.bytes(&self.tile_data_3);
+        w.bytes(&self.tile_map_1);
+        w.bytes(&self.tile_map_2);
+        w.bytes(&self.oam);
+        w.u16(self.x_clock);
+        w.bytes(&[
+            self.x_pixel,
+            self.ly,
+            self.mode.bits(),
+            self.lcdc.bits,
+            self.scx,
+            self.scy,
+            self.wy,
+            self.wx,
+            self.bg_palette,
+            self.obj_palette_0,
+            self.obj_palette_1,
+            self.lyc,
+            self.stat.bits,
+            self.object_priority_mode,
+            self.fine_scroll_discard,
+        ]);
+        w.bool(self.lyc_is_ly);
+        w.bool(self.previous_stat_interrupt);
+        w.bool(self.first_line_after_enable);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.fill(&mut self.tile_data_1)?;
+        r.fill(&mut self.tile_data_2)?;
+        r.fill(&mut self.tile_data_3)?;
+        r.fill(&mut self.tile_map_1)?;
+        r.fill(&mut self.tile_map_2)?;
+        r.fill(&mut self.oam)?;
+        self.x_clock = r.u16()?;
+        let [x_pixel, ly, mode, lcdc, scx, scy, wy, wx, bgp, obp0, obp1, lyc, stat, opri, discard] =
+            r.array()?;
+        self.x_pixel = x_pixel;
+        self.ly = ly;
+        self.mode = match mode {
+            0b00 => Mode::HBlank0,
+            0b01 => Mode::VBlank1,
+            0b10 => Mode::OAMSearch2,
+            0b11 => Mode::LCDOn3,
+            _ => return Err(StateError::Invalid("PPU mode")),
+        };
+        self.lcdc = LCDC::from_bits_truncate(lcdc);
+        self.scx = scx;
+        self.scy = scy;
+        self.wy = wy;
+        self.wx = wx;
+        self.bg_palette = bgp;
+        self.obj_palette_0 = obp0;
+        self.obj_palette_1 = obp1;
+        self.lyc = lyc;
+        self.stat = Stat::from_bits_truncate(stat);
+        self.object_priority_mode = opri;
+        self.fine_scroll_discard = discard;
+        self.lyc_is_ly = r.bool()?;
+        self.previous_stat_interrupt = r.bool()?;
+        self.first_line_after_enable = r.bool()?;
+        self.frame_cycles = None;
+        // Restored mid-line, redo the scan of the current line
+        if self.mode == Mode::LCDOn3 {
+            self.oam_scan();
+        } else {
+            self.line_sprites.clear();
+        }
+        Ok(())
+    }
+}
+
 impl Default for Ppu {
     fn default() -> Self {
         Self {
+            model: Model::default(),
             tile_data_1: [0; 0x800],
             tile_data_2: [0; 0x800],
             tile_data_3: [0; 0x800],
             tile_map_1: [0; 0x400],
             tile_map_2: [0; 0x400],
+            oam: [0; 0xA0],
             x_clock: 0,
             x_pixel: 0,
             ly: 144,
             mode: Mode::VBlank1,
-            lcdc: LCDC::empty(),
+            lcdc: LCDC::LCD_PPU_ENABLE | LCDC::BG_WINDOW_TILE_DATA_AREA | LCDC::BG_WINDOW_ENABLE,
             scx: 0,
             scy: 0,
-            bg_palette: 0,
+            wy: 0,
+            wx: 0,
+            bg_palette: 0xFC,
+            obj_palette_0: 0,
+            obj_palette_1: 0,
+            sprite_limit: Some(10),
+            line_sprites: Vec::with_capacity(40),
+            line_sprite_height: 8,
             frame_buffer: Buffer::boxed(),
+            back_buffer: None,
             lyc: 0,
             stat: Stat::empty(),
             lyc_is_ly: false,
             previous_stat_interrupt: false,
+            frame_skip: 0,
+            frames_skipped: 0,
+            skip_current_frame: false,
+            first_line_after_enable: false,
+            fine_scroll_discard: 0,
+            object_priority_mode: 0,
+            frame_sink: None,
+            scanline_sink: None,
+            pixel_events: true,
+            frame_events: true,
+            vram_events: false,
+            frame_buffer_borrowed: Cell::new(false),
+            frame_completed: false,
+            completed_frame: None,
+            frame_polled: Cell::new(false),
+            render_current_frame: true,
+            frame_cycles: None,
+            last_frame_cycles: 0,
         }
     }
 }
@@ -441,3 +1185,702 @@ impl std::fmt::Display for Ppu {
         writeln!(f, "STAT: {:?}", self.stat)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    const DOTS_PER_FRAME: usize = 154 * 456;
+
+    #[derive(Debug, Default)]
+    struct TestInterruptController {
+        vblank_count: usize,
+    }
+
+    impl InterruptContext for TestInterruptController {
+        fn raise_interrupt(&mut self, interrupt: Interrupt) {
+            if interrupt == Interrupt::VBlank {
+                self.vblank_count += 1;
+            }
+        }
+    }
+
+    fn enabled_ppu() -> Ppu {
+        Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE,
+            ..Ppu::default()
+        }
+    }
+
+    #[test]
+    fn frame_skip() {
+        let mut ppu = enabled_ppu();
+        ppu.set_frame_skip(2);
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 6 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        let frames: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ExecutionEvent::FrameReady(_) => Some(true),
+                ExecutionEvent::FrameSkipped => Some(false),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames, [true, false, false, true, false, false]);
+        assert_eq!(ctx.vblank_count, 6);
+        let pixels = events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::PpuPixelPushed(..)))
+            .count();
+        assert_eq!(pixels, 2 * 160 * 144);
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingInterrupts {
+        dot: usize,
+        raised: Vec<(usize, Interrupt)>,
+    }
+
+    impl InterruptContext for RecordingInterrupts {
+        fn raise_interrupt(&mut self, interrupt: Interrupt) {
+            self.raised.push((self.dot, interrupt));
+        }
+    }
+
+    #[test]
+    fn vblank_interrupt_timing() {
+        let mut ppu = Ppu::default();
+        let mut ctx = RecordingInterrupts::default();
+        let mut events = Vec::new();
+        ppu.write(0xFF40, 0).unwrap();
+        ppu.write(0xFF40, LCDC::LCD_PPU_ENABLE.bits).unwrap();
+        ppu.write(0xFF41, Stat::VBLANK_INTERRUPT.bits).unwrap();
+
+        let mut vblank_start = None;
+        for dot in 1..=DOTS_PER_FRAME {
+            ctx.dot = dot;
+            ppu.tick(&mut ctx, &mut events);
+            if vblank_start.is_none() && ppu.read(0xFF44) == Some(144) {
+                vblank_start = Some(dot);
+            }
+        }
+        // The first line after enabling is 4 dots short
+        let vblank_start = vblank_start.unwrap();
+        assert_eq!(vblank_start, 144 * 456 - 4);
+        assert_eq!(
+            ctx.raised,
+            [
+                (vblank_start, Interrupt::VBlank),
+                (vblank_start, Interrupt::LcdStat)
+            ]
+        );
+    }
+
+    #[test]
+    fn stat_sources_share_one_edge() {
+        let mut ppu = Ppu::default();
+        let mut ctx = RecordingInterrupts::default();
+        let mut events = Vec::new();
+        ppu.write(0xFF40, 0).unwrap();
+        ppu.write(0xFF40, LCDC::LCD_PPU_ENABLE.bits).unwrap();
+        ppu.write(0xFF45, 5).unwrap();
+        ppu.write(
+            0xFF41,
+            (Stat::OAM_INTERRUPT | Stat::LYC_IS_LY_INTERRUPT).bits,
+        )
+        .unwrap();
+
+        for dot in 1..=DOTS_PER_FRAME {
+            ctx.dot = dot;
+            ppu.tick(&mut ctx, &mut events);
+        }
+        // The first line after enabling is 4 dots short
+        let line_start = |ly: usize| 452 + (ly - 1) * 456;
+        let raised: Vec<_> = ctx
+            .raised
+            .iter()
+            .filter(|(dot, interrupt)| {
+                *interrupt == Interrupt::LcdStat && (line_start(4)..line_start(8)).contains(dot)
+            })
+            .map(|(dot, _)| *dot)
+            .collect();
+        // Mode 2 and LYC rise together on line 5, and LYC still holds the line high when mode 2 of
+        // line 6 starts
+        assert_eq!(raised, [line_start(4), line_start(5), line_start(7)]);
+    }
+
+    #[test]
+    fn decoded_registers() {
+        let mut ppu = Ppu::default();
+        ppu.write(0xFF40, 0b11100111).unwrap();
+        ppu.write(0xFF41, 0b01001000).unwrap();
+        // Booted in VBlank at LY 144
+        ppu.write(0xFF45, 144).unwrap();
+        ppu.tick(&mut TestInterruptController::default(), &mut Vec::new());
+
+        let registers = ppu.registers();
+        assert!(registers.lcd_enabled());
+        assert_eq!(registers.window_tile_map(), 0x9C00);
+        assert!(registers.window_enabled());
+        assert_eq!(registers.bg_window_tile_data(), 0x8800);
+        assert_eq!(registers.bg_tile_map(), 0x9800);
+        assert_eq!(registers.obj_size(), 16);
+        assert!(registers.obj_enabled());
+        assert!(registers.bg_window_enabled());
+
+        assert!(registers.lyc_interrupt_enabled());
+        assert!(!registers.oam_interrupt_enabled());
+        assert!(!registers.vblank_interrupt_enabled());
+        assert!(registers.hblank_interrupt_enabled());
+        assert!(registers.lyc_is_ly());
+    }
+
+    #[test]
+    fn first_line_after_enable() {
+        let mut ppu = Ppu::default();
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        ppu.write(0xFF40, 0).unwrap();
+        ppu.write(0xFF40, LCDC::LCD_PPU_ENABLE.bits).unwrap();
+        assert_eq!(ppu.read(0xFF44), Some(0));
+        assert_eq!(ppu.read(0xFF41).unwrap() & 0b11, Mode::HBlank0.bits());
+
+        // No mode 2 on the first line, and it is 4 dots shorter
+        let mut dots = 0;
+        while ppu.read(0xFF41).unwrap() & 0b11 != Mode::LCDOn3.bits() {
+            ppu.tick(&mut ctx, &mut events);
+            dots += 1;
+        }
+        assert_eq!(dots, 76);
+        while ppu.read(0xFF44) == Some(0) {
+            ppu.tick(&mut ctx, &mut events);
+            dots += 1;
+        }
+        assert_eq!(dots, 452);
+        assert_eq!(ppu.read(0xFF41).unwrap() & 0b11, Mode::OAMSearch2.bits());
+
+        while ctx.vblank_count == 0 {
+            ppu.tick(&mut ctx, &mut events);
+            dots += 1;
+        }
+        assert_eq!(dots, 452 + 143 * 456);
+    }
+
+    #[test]
+    fn fine_scroll_penalty() {
+        let mut ppu = Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE | LCDC::BG_WINDOW_TILE_DATA_AREA | LCDC::BG_WINDOW_ENABLE,
+            scx: 3,
+            ..Ppu::default()
+        };
+        // Only the leftmost pixel of every tile is set
+        for line in 0..8 {
+            ppu.tile_data_1[line * 2] = 0b10000000;
+        }
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 2 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        let switches: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ExecutionEvent::PpuModeSwitch { mode, x, y: 1 } => Some((*mode, *x)),
+                _ => None,
+            })
+            .collect();
+        assert!(switches.contains(&(Mode::LCDOn3, 80)));
+        assert!(switches.contains(&(Mode::HBlank0, 80 + 160 + 3)));
+
+        let set_pixels: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                ExecutionEvent::PpuPixelPushed(x, 1, ColorId::One) => Some(*x),
+                _ => None,
+            })
+            .take(3)
+            .collect();
+        assert_eq!(set_pixels, [5, 13, 21]);
+    }
+
+    // Solid color 3 background, a color 1 sprite behind it in the top left corner
+    fn bg_enable_pixels(model: Model, bg_window_enable: bool) -> (Color, Color) {
+        let mut lcdc = LCDC::LCD_PPU_ENABLE | LCDC::BG_WINDOW_TILE_DATA_AREA | LCDC::OBJ_ENABLE;
+        lcdc.set(LCDC::BG_WINDOW_ENABLE, bg_window_enable);
+        let mut ppu = Ppu {
+            lcdc,
+            bg_palette: 0b11100100,
+            obj_palette_0: 0b11100100,
+            ..Ppu::new(model)
+        };
+        ppu.set_frame_events(false);
+        ppu.tile_data_1[..16].fill(0xFF);
+        for line in 0..8 {
+            ppu.tile_data_1[16 + line * 2] = 0xFF;
+        }
+        ppu.oam[..4].copy_from_slice(&[16, 8, 1, SpriteFlags::BG_WINDOW_OVER_OBJ.bits()]);
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 2 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        (ppu.frame_buffer[0][0], ppu.frame_buffer[0][8])
+    }
+
+    #[test]
+    fn dmg_bg_window_enable() {
+        assert_eq!(
+            bg_enable_pixels(Model::Dmg, true),
+            (Color::Black, Color::Black)
+        );
+        assert_eq!(
+            bg_enable_pixels(Model::Dmg, false),
+            (Color::LightGrey, Color::White)
+        );
+    }
+
+    #[test]
+    fn cgb_master_priority() {
+        assert_eq!(
+            bg_enable_pixels(Model::Cgb, true),
+            (Color::Black, Color::Black)
+        );
+        assert_eq!(
+            bg_enable_pixels(Model::Cgb, false),
+            (Color::LightGrey, Color::Black)
+        );
+    }
+
+    // 12 sprites next to each other on the first lines, all black
+    fn ppu_with_sprites(sprite_limit: Option<u8>) -> Ppu {
+        let mut ppu = Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE | LCDC::OBJ_ENABLE,
+            obj_palette_0: 0b11100100,
+            ..Ppu::default()
+        };
+        ppu.set_sprite_limit(sprite_limit);
+        ppu.tile_data_1[..16].fill(0xFF);
+        for i in 0..12 {
+            ppu.oam[i * 4] = 16;
+            ppu.oam[i * 4 + 1] = 8 + 8 * i as u8;
+        }
+        ppu
+    }
+
+    fn sprite_pixels_on_first_line(mut ppu: Ppu) -> usize {
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::PpuPixelPushed(_, 0, ColorId::Three)))
+            .count()
+    }
+
+    #[test]
+    fn sprite_limit() {
+        assert_eq!(sprite_pixels_on_first_line(ppu_with_sprites(Some(10))), 80);
+        assert_eq!(sprite_pixels_on_first_line(ppu_with_sprites(None)), 96);
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingSink(usize);
+
+    impl FrameSink for Rc<RefCell<CountingSink>> {
+        fn frame(&mut self, _buffer: &Buffer) {
+            self.borrow_mut().0 += 1;
+        }
+    }
+
+    #[test]
+    fn ly_153_reads_0_early() {
+        let mut ppu = Ppu {
+            lyc: 0,
+            ..Ppu::default()
+        };
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..(153 - 144) * 456 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        assert_eq!(ppu.read(0xFF44), Some(153));
+
+        // LYC=0 already matches on line 153
+        let mut line = Vec::new();
+        for _ in 0..456 {
+            ppu.tick(&mut ctx, &mut events);
+            line.push((ppu.read(0xFF44).unwrap(), ppu.mode, ppu.lyc_is_ly));
+        }
+        assert_eq!(line[..3], [(153, Mode::VBlank1, false); 3]);
+        assert_eq!(line[3..455], [(0, Mode::VBlank1, true); 452]);
+        assert_eq!(line[455], (0, Mode::OAMSearch2, true));
+    }
+
+    #[test]
+    fn partial_frame_is_blank() {
+        let mut ppu = Ppu {
+            bg_palette: 0b11100100,
+            ..Ppu::default()
+        };
+        ppu.set_frame_events(false);
+        ppu.tile_data_1[..16].fill(0xFF);
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        assert!(ppu.frame_buffer.flatten().all(|c| c == Color::Black));
+
+        // 10 lines into the next frame
+        for _ in 0..(154 - 144 + 10) * 456 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        assert_eq!(ppu.ly, 10);
+        assert_eq!(ppu.frame_buffer[9][0], Color::Black);
+        assert_eq!(ppu.frame_buffer[10][0], Color::White);
+        assert_eq!(ppu.frame_buffer[143][159], Color::White);
+    }
+
+    #[test]
+    fn frame_sink_keeps_rendering() {
+        let mut ppu = enabled_ppu();
+        ppu.set_pixel_events(false);
+        ppu.set_frame_events(false);
+        let sink = Rc::new(RefCell::new(CountingSink::default()));
+        ppu.set_frame_sink(Some(Box::new(sink.clone())));
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 3 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        assert_eq!(sink.borrow().0, 3);
+        assert!(ppu.pixels_wanted());
+        ppu.set_frame_sink(None);
+        assert!(!ppu.pixels_wanted());
+        ppu.frame_buffer();
+        assert!(ppu.pixels_wanted());
+        assert!(!ppu.pixels_wanted());
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingSinks {
+        lines: Vec<(u8, Line)>,
+        frames: Vec<Buffer>,
+    }
+
+    impl ScanlineSink for Rc<RefCell<RecordingSinks>> {
+        fn line(&mut self, ly: u8, line: &Line) {
+            self.borrow_mut().lines.push((ly, line.clone()));
+        }
+    }
+
+    impl FrameSink for Rc<RefCell<RecordingSinks>> {
+        fn frame(&mut self, buffer: &Buffer) {
+            self.borrow_mut().frames.push(buffer.clone());
+        }
+    }
+
+    #[test]
+    fn scanline_sink_matches_frame() {
+        let mut ppu = ppu_with_sprites(None);
+        ppu.set_pixel_events(false);
+        ppu.set_frame_events(false);
+        let sinks = Rc::new(RefCell::new(RecordingSinks::default()));
+        ppu.set_scanline_sink(Some(Box::new(sinks.clone())));
+        ppu.set_frame_sink(Some(Box::new(sinks.clone())));
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 2 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        let sinks = sinks.borrow();
+        assert_eq!(sinks.frames.len(), 2);
+        assert_eq!(sinks.lines.len(), 2 * 144);
+        for (frame, lines) in sinks.frames.iter().zip(sinks.lines.chunks(144)) {
+            for (i, (ly, line)) in lines.iter().enumerate() {
+                assert_eq!(*ly as usize, i);
+                assert_eq!(*line, frame[i]);
+            }
+        }
+        assert_eq!(sinks.lines[0].1[0], Color::Black);
+        assert_eq!(sinks.lines[8].1[0], Color::White);
+    }
+
+    #[test]
+    fn scanline_sources() {
+        let mut ppu = Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE
+                | LCDC::OBJ_ENABLE
+                | LCDC::BG_WINDOW_TILE_DATA_AREA
+                | LCDC::BG_WINDOW_ENABLE,
+            ..Ppu::default()
+        };
+        ppu.tile_data_1[..16].fill(0xFF);
+        // OAM 2 on pixels 16-23 of the first 8 lines
+        ppu.oam[8..12].copy_from_slice(&[16, 24, 0, 0]);
+        let sinks = Rc::new(RefCell::new(RecordingSinks::default()));
+        ppu.set_scanline_sink(Some(Box::new(sinks.clone())));
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        let sinks = sinks.borrow();
+        let sources = sinks.lines[0].1.sources().unwrap();
+        assert_eq!(sources[15], PixelSource::Background);
+        assert!(sources[16..24].iter().all(|s| *s == PixelSource::Object(2)));
+        assert_eq!(sources[24], PixelSource::Background);
+        assert_eq!(
+            sinks.lines[8].1.sources().unwrap()[16],
+            PixelSource::Background
+        );
+    }
+
+    #[test]
+    fn no_sources_without_pixel_events() {
+        let mut ppu = ppu_with_sprites(None);
+        ppu.set_pixel_events(false);
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        assert_eq!(ppu.frame_buffer()[0].sources(), None);
+    }
+
+    #[derive(Debug, Default)]
+    struct BufferAddresses(Vec<*const Buffer>);
+
+    impl FrameSink for Rc<RefCell<BufferAddresses>> {
+        fn frame(&mut self, buffer: &Buffer) {
+            self.borrow_mut().0.push(buffer);
+        }
+    }
+
+    #[test]
+    fn double_buffering() {
+        let mut ppu = enabled_ppu();
+        ppu.set_frame_events(false);
+        ppu.set_double_buffering(true);
+        let addresses = Rc::new(RefCell::new(BufferAddresses::default()));
+        ppu.set_frame_sink(Some(Box::new(addresses.clone())));
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 4 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        let addresses = &addresses.borrow().0;
+        assert_eq!(addresses.len(), 4);
+        assert_ne!(addresses[0], addresses[1]);
+        assert_eq!(addresses[0], addresses[2]);
+        assert_eq!(addresses[1], addresses[3]);
+        // The completed frame stays readable while the next one is drawn
+        assert_eq!(ppu.frame_buffer() as *const Buffer, addresses[3]);
+    }
+
+    #[test]
+    fn double_buffering_with_frame_events() {
+        let mut ppu = Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE | LCDC::BG_WINDOW_ENABLE,
+            bg_palette: 0xFF,
+            ..Ppu::default()
+        };
+        ppu.set_pixel_events(false);
+        ppu.set_double_buffering(true);
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME * 2 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        let frames: Vec<_> = events
+            .into_iter()
+            .filter_map(|e| match e {
+                ExecutionEvent::FrameReady(buffer) => Some(buffer),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[1].flatten().all(|c| c == Color::Black));
+        assert!(ppu.frame_buffer().flatten().eq(frames[1].flatten()));
+    }
+
+    #[test]
+    fn frame_ready_until_taken() {
+        let mut ppu = enabled_ppu();
+        ppu.set_frame_events(false);
+        ppu.set_pixel_events(false);
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        while ctx.vblank_count == 0 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+
+        assert!(ppu.frame_ready());
+        assert!(ppu.take_frame().is_some());
+        assert!(!ppu.frame_ready());
+        assert!(ppu.take_frame().is_none());
+    }
+
+    #[test]
+    fn lcd_off_delivers_white_frame() {
+        let mut ppu = Ppu {
+            lcdc: LCDC::empty(),
+            bg_palette: 0xFF,
+            ..Ppu::default()
+        };
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        ppu.write_with_events(0xFF40, 0x91, &mut events).unwrap();
+        for _ in 0..456 * 10 {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        assert_eq!(ppu.frame_buffer[0][0], Color::Black);
+        events.clear();
+
+        ppu.write_with_events(0xFF40, 0, &mut events).unwrap();
+        let frames: Vec<_> = events
+            .into_iter()
+            .filter_map(|e| match e {
+                ExecutionEvent::FrameReady(buffer) => Some(buffer),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].flatten().all(|c| c == Color::White));
+
+        // Writing to an already disabled LCD doesn't deliver another frame
+        let mut events = Vec::new();
+        ppu.write_with_events(0xFF40, 0, &mut events).unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn obj_size_change_during_line() {
+        let mut ppu = Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE | LCDC::OBJ_ENABLE | LCDC::OBJ_SIZE,
+            ly: 10,
+            ..Ppu::default()
+        };
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0, SpriteFlags::Y_FLIP.bits()]);
+        // Row 5 of the 8x16 sprite, line 10 flipped
+        ppu.tile_data_1[10] = 0xFF;
+        ppu.tile_data_1[11] = 0xFF;
+        ppu.oam_scan();
+
+        ppu.write(0xFF40, (LCDC::LCD_PPU_ENABLE | LCDC::OBJ_ENABLE).bits)
+            .unwrap();
+        let sprite = ppu.line_sprites[0];
+        assert_eq!(ppu.get_sprite_pixel_color_id(&sprite), ColorId::Three);
+    }
+
+    #[test]
+    fn tile_map_indices() {
+        let mut ppu = Ppu::default();
+        ppu.write(0x9800 + 3 * 32 + 5, 0x42).unwrap();
+        ppu.write(0x9FFF, 0x17).unwrap();
+
+        let low = ppu.tile_map(TileMap::Low);
+        assert_eq!(low[3][5], 0x42);
+        assert_eq!(low[5][3], 0);
+        let high = ppu.tile_map(TileMap::High);
+        assert_eq!(high[31][31], 0x17);
+        assert_eq!(high[3][5], 0);
+    }
+
+    #[test]
+    fn vram_written() {
+        let mut ppu = Ppu::default();
+        let mut events = Vec::new();
+        ppu.write_with_events(0x8010, 0x3C, &mut events).unwrap();
+        assert!(events.is_empty());
+
+        ppu.set_vram_events(true);
+        ppu.write_with_events(0x8010, 0x7E, &mut events).unwrap();
+        ppu.write_with_events(0xFE00, 0x01, &mut events).unwrap();
+        ppu.mode = Mode::LCDOn3;
+        ppu.write_with_events(0x9800, 0x01, &mut events).unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [ExecutionEvent::VramWritten { address, value }] if address.0 == 0x8010 && value.0 == 0x7E
+        ));
+    }
+
+    #[test]
+    fn palette_changed() {
+        let mut ppu = Ppu::default();
+        let mut events = Vec::new();
+        let fade = [0b11100100, 0b10010000, 0b01000000, 0b00000000];
+        for value in fade {
+            ppu.write_with_events(0xFF47, value, &mut events).unwrap();
+        }
+        ppu.write_with_events(0xFF42, 0x12, &mut events).unwrap();
+
+        let palettes: Vec<_> = events
+            .iter()
+            .map(|e| match e {
+                ExecutionEvent::PaletteChanged { which, value } => (*which, *value),
+                _ => panic!("Unexpected event {}", e),
+            })
+            .collect();
+        assert_eq!(palettes, fade.map(|v| (PaletteKind::Bg, v)));
+        assert_eq!(ppu.read(0xFF47), Some(0));
+    }
+
+    fn overlapping_sprite_color(object_priority_mode: u8) -> ColorId {
+        let mut ppu = Ppu {
+            lcdc: LCDC::LCD_PPU_ENABLE | LCDC::OBJ_ENABLE,
+            ..Ppu::new(Model::Cgb)
+        };
+        ppu.write(0xFF6C, object_priority_mode).unwrap();
+        // Tile 0 is color 3, tile 1 color 1
+        ppu.tile_data_1[..16].fill(0xFF);
+        ppu.tile_data_1[16..32]
+            .chunks_exact_mut(2)
+            .for_each(|c| c[0] = 0xFF);
+        // OAM 0 on pixels 8-15, OAM 1 on pixels 4-11
+        ppu.oam[..4].copy_from_slice(&[16, 16, 0, 0]);
+        ppu.oam[4..8].copy_from_slice(&[16, 12, 1, 0]);
+
+        let mut ctx = TestInterruptController::default();
+        let mut events = Vec::new();
+        for _ in 0..DOTS_PER_FRAME {
+            ppu.tick(&mut ctx, &mut events);
+        }
+        events
+            .iter()
+            .find_map(|e| match e {
+                ExecutionEvent::PpuPixelPushed(8, 0, c) => Some(*c),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn cgb_object_priority() {
+        assert_eq!(overlapping_sprite_color(0), ColorId::Three);
+        assert_eq!(overlapping_sprite_color(1), ColorId::One);
+    }
+
+    #[test]
+    fn dmg_ignores_opri() {
+        let mut ppu = Ppu::new(Model::Dmg);
+        ppu.write(0xFF6C, 0x00).unwrap();
+        assert_eq!(ppu.read(0xFF6C), Some(0xFF));
+        assert!(ppu.priority_by_x());
+    }
+}
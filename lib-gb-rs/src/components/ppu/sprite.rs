@@ -0,0 +1,44 @@
+use bitflags::bitflags;
+
+bitflags! {
+    pub(super) struct SpriteFlags: u8 {
+        const BG_WINDOW_OVER_OBJ = 0b10000000;
+        const Y_FLIP = 0b01000000;
+        const X_FLIP = 0b00100000;
+        const PALETTE = 0b00010000;
+    }
+}
+
+// Coordinates as stored in OAM: x is offset by 8 and y by 16
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Sprite {
+    pub index: u8,
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: SpriteFlags,
+}
+
+impl Sprite {
+    pub fn from_oam(index: usize, entry: &[u8]) -> Self {
+        Self {
+            index: index as u8,
+            y: entry[0],
+            x: entry[1],
+            tile: entry[2],
+            flags: SpriteFlags::from_bits_truncate(entry[3]),
+        }
+    }
+
+    pub fn is_on_line(&self, ly: u8, height: u8) -> bool {
+        let line = ly as u16 + 16;
+        let y = self.y as u16;
+        y <= line && line < y + height as u16
+    }
+
+    pub fn covers(&self, x: u8) -> bool {
+        let x = x as u16 + 8;
+        let sprite_x = self.x as u16;
+        sprite_x <= x && x < sprite_x + 8
+    }
+}
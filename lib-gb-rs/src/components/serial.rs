@@ -1,6 +1,7 @@
 use bitflags::bitflags;
 
 use crate::components::interrupt_controller::Interrupt;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::{Addressable, EventContext, ExecutionEvent, HexByte, InterruptContext};
 
 bitflags! {
@@ -22,6 +23,10 @@ pub struct Serial {
 }
 
 impl Serial {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
     pub fn tick<C: InterruptContext, E: EventContext>(&mut self, ctx: &mut C, e: &mut E) {
         self.clock_counter += 1;
         if self.clock_counter == 512 {
@@ -73,3 +78,22 @@ impl Addressable for Serial {
         }
     }
 }
+
+impl SaveState for Serial {
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control.bits);
+        w.u16(self.clock_counter);
+        w.u8(self.bit_counter);
+        w.u8(self.data_copy);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data = r.u8()?;
+        self.control = Control::from_bits_truncate(r.u8()?);
+        self.clock_counter = r.u16()?;
+        self.bit_counter = r.u8()?;
+        self.data_copy = r.u8()?;
+        Ok(())
+    }
+}
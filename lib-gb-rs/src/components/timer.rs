@@ -1,4 +1,5 @@
 use crate::components::interrupt_controller::Interrupt;
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::{Addressable, InterruptContext};
 
 #[derive(Debug, Copy, Clone)]
@@ -53,29 +54,45 @@ pub struct Timer {
     timer_enabled: bool,
     timer_control: TimerControl,
     timer_was_high_last_tick: bool,
-    timer_overflowed_last_tick: bool,
+    overflow_delay: u8,
+    timer_reloaded_this_tick: bool,
 }
 
 impl Timer {
     const TIMER_ENABLE_BIT: u8 = 0b00000100;
+    // TIMA reads 0 for one M-cycle after overflowing before TMA is reloaded
+    const OVERFLOW_DELAY: u8 = 4;
 
     pub fn tick<I: InterruptContext>(&mut self, context: &mut I) {
         self.divider = self.divider.wrapping_add(1);
         let is_high = self.divider & self.timer_control.mask() > 0;
         let high_and_enabled = is_high && self.timer_enabled;
-        if self.timer_overflowed_last_tick {
-            context.raise_interrupt(Interrupt::Timer);
-            self.timer_counter = self.timer_modulo;
-            self.timer_overflowed_last_tick = false;
+        self.timer_reloaded_this_tick = false;
+        if self.overflow_delay > 0 {
+            self.overflow_delay -= 1;
+            if self.overflow_delay == 0 {
+                context.raise_interrupt(Interrupt::Timer);
+                self.timer_counter = self.timer_modulo;
+                self.timer_reloaded_this_tick = true;
+            }
         }
         if !high_and_enabled && self.timer_was_high_last_tick {
             self.timer_counter = self.timer_counter.wrapping_add(1);
             if self.timer_counter == 0 {
-                self.timer_overflowed_last_tick = true;
+                self.overflow_delay = Self::OVERFLOW_DELAY;
             }
         }
         self.timer_was_high_last_tick = high_and_enabled;
     }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    // DIV is the upper byte
+    pub fn div_counter(&self) -> u16 {
+        self.divider
+    }
 }
 
 impl Addressable for Timer {
@@ -103,11 +120,18 @@ impl Addressable for Timer {
                 Some(())
             }
             0xFF05 => {
-                self.timer_counter = value;
+                // Ignored while TMA is being reloaded, cancels the reload and interrupt during the delay
+                if !self.timer_reloaded_this_tick {
+                    self.timer_counter = value;
+                    self.overflow_delay = 0;
+                }
                 Some(())
             }
             0xFF06 => {
                 self.timer_modulo = value;
+                if self.timer_reloaded_this_tick {
+                    self.timer_counter = value;
+                }
                 Some(())
             }
             0xFF07 => {
@@ -144,6 +168,31 @@ impl std::fmt::Display for Timer {
     }
 }
 
+impl SaveState for Timer {
+    fn save(&self, w: &mut StateWriter) {
+        w.u16(self.divider);
+        w.u8(self.timer_counter);
+        w.u8(self.timer_modulo);
+        w.bool(self.timer_enabled);
+        w.u8(self.timer_control.into_bits());
+        w.bool(self.timer_was_high_last_tick);
+        w.u8(self.overflow_delay);
+        w.bool(self.timer_reloaded_this_tick);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.divider = r.u16()?;
+        self.timer_counter = r.u8()?;
+        self.timer_modulo = r.u8()?;
+        self.timer_enabled = r.bool()?;
+        self.timer_control = TimerControl::from_bits(r.u8()?);
+        self.timer_was_high_last_tick = r.bool()?;
+        self.overflow_delay = r.u8()?;
+        self.timer_reloaded_this_tick = r.bool()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -178,7 +227,9 @@ mod tests {
         }
         assert_eq!(timer.timer_counter, 0);
         dbg!(&mut timer);
-        timer.tick(&mut context);
+        for _ in 0..Timer::OVERFLOW_DELAY {
+            timer.tick(&mut context);
+        }
         dbg!(&mut timer);
         assert!(context.triggered_interrupt)
     }
@@ -193,12 +244,12 @@ mod tests {
             timer_control: TimerControl::Div16,
             ..Timer::default()
         };
-        for _ in 0..(16 * 256) + 1 {
+        for _ in 0..(16 * 256) + Timer::OVERFLOW_DELAY as usize {
             timer.tick(&mut context);
         }
         assert!(context.triggered_interrupt, "first interrupt");
         context.triggered_interrupt = false;
-        for _ in 0..(16 * 256) + 1 {
+        for _ in 0..(16 * 256) + Timer::OVERFLOW_DELAY as usize {
             timer.tick(&mut context);
         }
         assert!(context.triggered_interrupt, "second interrupt");
@@ -220,6 +271,9 @@ mod tests {
         }
         dbg!(&timer);
         dbg!(&context);
+        for _ in 0..Timer::OVERFLOW_DELAY - 1 {
+            timer.tick(&mut context);
+        }
         assert!(!context.triggered_interrupt, "first interrupt in 1");
         timer.tick(&mut context);
         assert!(context.triggered_interrupt, "first interrupt");
@@ -295,4 +349,105 @@ mod tests {
         timer.tick(&mut context);
         assert!(!context.triggered_interrupt)
     }
+
+    #[test]
+    fn div_counter_wraps() {
+        let mut context = TestInterruptController::default();
+        let mut timer = Timer::default();
+        for _ in 0..0x10000 + 0x1234 {
+            timer.tick(&mut context);
+        }
+        assert_eq!(timer.div_counter(), 0x1234);
+        assert_eq!(timer.read(0xFF04), Some(0x12));
+    }
+
+    // Records on which tick each interrupt was raised
+    #[derive(Debug, Default)]
+    struct RecordingInterruptSink {
+        tick: usize,
+        interrupts: Vec<usize>,
+    }
+
+    impl InterruptContext for RecordingInterruptSink {
+        fn raise_interrupt(&mut self, interrupt: Interrupt) {
+            assert_eq!(interrupt, Interrupt::Timer);
+            self.interrupts.push(self.tick);
+        }
+    }
+
+    #[test]
+    fn interrupt_per_period_4096hz() {
+        let mut context = RecordingInterruptSink::default();
+        let mut timer = Timer::default();
+        timer.write(0xFF06, 0xFF).unwrap();
+        timer.write(0xFF05, 0xFF).unwrap();
+        timer.write(0xFF07, 0b100).unwrap();
+        for _ in 0..4 * 1024 {
+            context.tick += 1;
+            timer.tick(&mut context);
+        }
+
+        // TIMA overflows every 1024 cycles, the interrupt follows one M-cycle later
+        assert_eq!(context.interrupts, [1028, 2052, 3076]);
+    }
+
+    fn overflowed_timer(context: &mut TestInterruptController) -> Timer {
+        let mut timer = Timer {
+            timer_enabled: true,
+            timer_counter: 0xFF,
+            timer_modulo: 0x10,
+            timer_control: TimerControl::Div16,
+            ..Timer::default()
+        };
+        for _ in 0..16 {
+            timer.tick(context);
+        }
+        assert_eq!(timer.timer_counter, 0);
+        timer
+    }
+
+    #[test]
+    fn tima_write_during_overflow_delay() {
+        let mut context = TestInterruptController::default();
+        let mut timer = overflowed_timer(&mut context);
+        timer.tick(&mut context);
+        timer.write(0xFF05, 0x42).unwrap();
+        for _ in 0..Timer::OVERFLOW_DELAY {
+            timer.tick(&mut context);
+        }
+        assert_eq!(timer.read(0xFF05), Some(0x42));
+        assert!(!context.triggered_interrupt);
+    }
+
+    #[test]
+    fn tima_write_during_reload() {
+        let mut context = TestInterruptController::default();
+        let mut timer = overflowed_timer(&mut context);
+        for _ in 0..Timer::OVERFLOW_DELAY {
+            timer.tick(&mut context);
+        }
+        assert!(context.triggered_interrupt);
+        timer.write(0xFF05, 0x42).unwrap();
+        assert_eq!(timer.read(0xFF05), Some(0x10));
+
+        timer.tick(&mut context);
+        timer.write(0xFF05, 0x42).unwrap();
+        assert_eq!(timer.read(0xFF05), Some(0x42));
+    }
+
+    #[test]
+    fn tma_write_during_reload() {
+        let mut context = TestInterruptController::default();
+        let mut timer = overflowed_timer(&mut context);
+        for _ in 0..Timer::OVERFLOW_DELAY {
+            timer.tick(&mut context);
+        }
+        timer.write(0xFF06, 0x20).unwrap();
+        assert_eq!(timer.read(0xFF05), Some(0x20));
+
+        timer.tick(&mut context);
+        timer.write(0xFF06, 0x30).unwrap();
+        assert_eq!(timer.read(0xFF05), Some(0x20));
+        assert_eq!(timer.read(0xFF06), Some(0x30));
+    }
 }
@@ -1,3 +1,4 @@
+use crate::save_state::{SaveState, StateError, StateReader, StateWriter};
 use crate::{Addressable, KIB};
 
 pub struct WorkRam {
@@ -10,12 +11,19 @@ impl Default for WorkRam {
     }
 }
 
+impl WorkRam {
+    pub fn load_bytes(&mut self, offset: u16, data: &[u8]) {
+        let offset = offset as usize;
+        self.ram[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
 impl Addressable for WorkRam {
     fn read(&self, address: u16) -> Option<u8> {
         let a = address as usize;
         match address {
             0xC000..=0xDFFF => Some(self.ram[a - 0xC000]),
-            // TODO Echo RAM, trace log somehow?
+            0xE000..=0xFDFF => Some(self.ram[a - 0xE000]),
             _ => None,
         }
     }
@@ -27,8 +35,21 @@ impl Addressable for WorkRam {
                 self.ram[a - 0xC000] = value;
                 Some(())
             }
-            // TODO Echo RAM, trace log somehow?
+            0xE000..=0xFDFF => {
+                self.ram[a - 0xE000] = value;
+                Some(())
+            }
             _ => None,
         }
     }
 }
+
+impl SaveState for WorkRam {
+    fn save(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.fill(&mut self.ram)
+    }
+}
@@ -5,30 +5,52 @@ use crate::components::cpu::{Register16, Register8};
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Immediate8(pub u8);
 
+impl Immediate8 {
+    pub fn value(&self) -> u8 {
+        self.0
+    }
+
+    // As the signed offset of JR
+    pub fn offset(&self) -> i8 {
+        self.0 as i8
+    }
+
+    // JR offsets count from the end of the 2 byte instruction at `address`
+    pub fn relative_target(&self, address: u16) -> u16 {
+        address.wrapping_add(2).wrapping_add(self.offset() as u16)
+    }
+}
+
 impl std::fmt::Display for Immediate8 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:#04x}", self.0)
+        write!(f, "${:02X}", self.0)
     }
 }
 
 impl std::fmt::Debug for Immediate8 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:#04x}", self.0)
     }
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Immediate16(pub u16);
 
+impl Immediate16 {
+    pub fn value(&self) -> u16 {
+        self.0
+    }
+}
+
 impl std::fmt::Display for Immediate16 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:#06x}", self.0)
+        write!(f, "${:04X}", self.0)
     }
 }
 
 impl std::fmt::Debug for Immediate16 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:#06x}", self.0)
     }
 }
 
@@ -123,9 +145,396 @@ pub enum Instruction {
     Reset(ResetVector),
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Cycles {
+    Fixed(u8),
+    Conditional { taken: u8, not_taken: u8 },
+}
+
+impl Cycles {
+    pub fn for_branch(&self, taken: bool) -> u8 {
+        match *self {
+            Cycles::Fixed(cycles) => cycles,
+            Cycles::Conditional { taken: t, .. } if taken => t,
+            Cycles::Conditional { not_taken, .. } => not_taken,
+        }
+    }
+}
+
+impl Instruction {
+    // T-cycles, including the opcode fetch(es)
+    pub fn cycles(&self) -> Cycles {
+        let indirect = |r: &CommonRegister| *r == CommonRegister::HLIndirect;
+        let cycles = match self {
+            Instruction::LoadRegisterRegister(t, s) if indirect(t) || indirect(s) => 8,
+            Instruction::LoadRegisterRegister(_, _) => 4,
+            Instruction::LoadRegisterImmediate8(r, _) if indirect(r) => 12,
+            Instruction::LoadRegisterImmediate8(_, _) => 8,
+            Instruction::LoadAIndirectRegister(_) => 8,
+            Instruction::LoadAIndirectImmediate16(_) => 16,
+            Instruction::LoadIndirectRegisterA(_) => 8,
+            Instruction::LoadIndirectImmediate16A(_) => 16,
+            Instruction::LoadIOAIndirectImmediate8(_) => 12,
+            Instruction::LoadIOIndirectImmediate8A(_) => 12,
+            Instruction::LoadIOIndirectCA => 8,
+            Instruction::LoadIOAIndirectC => 8,
+            Instruction::LoadAIncrementHLIndirect => 8,
+            Instruction::LoadIncrementHLIndirectA => 8,
+            Instruction::LoadADecrementHLIndirect => 8,
+            Instruction::LoadDecrementHLIndirectA => 8,
+            Instruction::LoadRegisterImmediate16(_, _) => 12,
+            Instruction::LoadIndirectImmediate16SP(_) => 20,
+            Instruction::LoadSPHL => 8,
+            Instruction::Push(_) => 16,
+            Instruction::Pop(_) => 12,
+            Instruction::AluRegister(_, r) if indirect(r) => 8,
+            Instruction::AluRegister(_, _) => 4,
+            Instruction::AluImmediate(_, _) => 8,
+            Instruction::IncRegister8(r) | Instruction::DecRegister8(r) if indirect(r) => 12,
+            Instruction::IncRegister8(_) | Instruction::DecRegister8(_) => 4,
+            Instruction::DecimalAdjust => 4,
+            Instruction::Complement => 4,
+            Instruction::AddHLRegister(_) => 8,
+            Instruction::IncRegister16(_) => 8,
+            Instruction::DecRegister16(_) => 8,
+            Instruction::AddSPImmediate(_) => 16,
+            Instruction::LoadHLSPImmediate(_) => 12,
+            Instruction::RotateALeft
+            | Instruction::RotateALeftThroughCarry
+            | Instruction::RotateARight
+            | Instruction::RotateARightThroughCarry => 4,
+            Instruction::RotateShiftRegister(_, r) if indirect(r) => 16,
+            Instruction::RotateShiftRegister(_, _) => 8,
+            Instruction::BitRegister(_, r) if indirect(r) => 12,
+            Instruction::BitRegister(_, _) => 8,
+            Instruction::SetRegister(_, r) | Instruction::ResRegister(_, r) if indirect(r) => 16,
+            Instruction::SetRegister(_, _) | Instruction::ResRegister(_, _) => 8,
+            Instruction::Ccf
+            | Instruction::Scf
+            | Instruction::Nop
+            | Instruction::Halt
+            | Instruction::Stop
+            | Instruction::DI
+            | Instruction::EI => 4,
+            Instruction::JumpImmediate(_) => 16,
+            Instruction::JumpHL => 4,
+            Instruction::JumpConditionalImmediate(_, _) => {
+                return Cycles::Conditional {
+                    taken: 16,
+                    not_taken: 12,
+                }
+            }
+            Instruction::JumpRelative(_) => 12,
+            Instruction::JumpConditionalRelative(_, _) => {
+                return Cycles::Conditional {
+                    taken: 12,
+                    not_taken: 8,
+                }
+            }
+            Instruction::CallImmediate(_) => 24,
+            Instruction::CallConditionalImmediate(_, _) => {
+                return Cycles::Conditional {
+                    taken: 24,
+                    not_taken: 12,
+                }
+            }
+            Instruction::Return => 16,
+            Instruction::ReturnConditional(_) => {
+                return Cycles::Conditional {
+                    taken: 20,
+                    not_taken: 8,
+                }
+            }
+            Instruction::ReturnFromInterrupt => 16,
+            Instruction::Reset(_) => 16,
+        };
+
+        Cycles::Fixed(cycles)
+    }
+
+    // Decodes an opcode the same way execution does, with zeroed immediates.
+    // 0xCB is the prefix for `decode_cb` and decodes to None, like the illegal opcodes.
+    pub fn decode(opcode: u8) -> Option<Instruction> {
+        let x = (opcode & 0b11000000) >> 6;
+        let y = (opcode & 0b00111000) >> 3;
+        let z = opcode & 0b00000111;
+        let p = (y & 0b110) >> 1;
+        let q = y & 0b1;
+        let n = Immediate8(0);
+        let nn = Immediate16(0);
+        let instruction = match x {
+            0 => match z {
+                0 => match y {
+                    0 => Instruction::Nop,
+                    1 => Instruction::LoadIndirectImmediate16SP(nn),
+                    2 => Instruction::Stop,
+                    3 => Instruction::JumpRelative(n),
+                    y => Instruction::JumpConditionalRelative(JumpCondition::from_u8(y - 4), n),
+                },
+                1 => match q {
+                    0 => Instruction::LoadRegisterImmediate16(Register16::from_byte_sp(p), nn),
+                    _ => Instruction::AddHLRegister(Register16::from_byte_sp(p)),
+                },
+                2 => match (q, p) {
+                    (0, 0) => Instruction::LoadIndirectRegisterA(Register16::BC),
+                    (0, 1) => Instruction::LoadIndirectRegisterA(Register16::DE),
+                    (0, 2) => Instruction::LoadIncrementHLIndirectA,
+                    (0, _) => Instruction::LoadDecrementHLIndirectA,
+                    (_, 0) => Instruction::LoadAIndirectRegister(Register16::BC),
+                    (_, 1) => Instruction::LoadAIndirectRegister(Register16::DE),
+                    (_, 2) => Instruction::LoadAIncrementHLIndirect,
+                    (_, _) => Instruction::LoadADecrementHLIndirect,
+                },
+                3 => match q {
+                    0 => Instruction::IncRegister16(Register16::from_byte_sp(p)),
+                    _ => Instruction::DecRegister16(Register16::from_byte_sp(p)),
+                },
+                4 => Instruction::IncRegister8(CommonRegister::from_u8(y)),
+                5 => Instruction::DecRegister8(CommonRegister::from_u8(y)),
+                6 => Instruction::LoadRegisterImmediate8(CommonRegister::from_u8(y), n),
+                _ => match y {
+                    0 => Instruction::RotateALeft,
+                    1 => Instruction::RotateARight,
+                    2 => Instruction::RotateALeftThroughCarry,
+                    3 => Instruction::RotateARightThroughCarry,
+                    4 => Instruction::DecimalAdjust,
+                    5 => Instruction::Complement,
+                    6 => Instruction::Scf,
+                    _ => Instruction::Ccf,
+                },
+            },
+            1 => {
+                let target = CommonRegister::from_u8(y);
+                let source = CommonRegister::from_u8(z);
+                if target == CommonRegister::HLIndirect && source == CommonRegister::HLIndirect {
+                    Instruction::Halt
+                } else {
+                    Instruction::LoadRegisterRegister(target, source)
+                }
+            }
+            2 => Instruction::AluRegister(
+                ArithmeticOperation::from_u8(y),
+                CommonRegister::from_u8(z),
+            ),
+            _ => match z {
+                0 => match y {
+                    0..=3 => Instruction::ReturnConditional(JumpCondition::from_u8(y)),
+                    4 => Instruction::LoadIOIndirectImmediate8A(n),
+                    5 => Instruction::AddSPImmediate(n),
+                    6 => Instruction::LoadIOAIndirectImmediate8(n),
+                    _ => Instruction::LoadHLSPImmediate(n),
+                },
+                1 => match (q, p) {
+                    (0, p) => Instruction::Pop(Register16::from_byte_af(p)),
+                    (_, 0) => Instruction::Return,
+                    (_, 1) => Instruction::ReturnFromInterrupt,
+                    (_, 2) => Instruction::JumpHL,
+                    (_, _) => Instruction::LoadSPHL,
+                },
+                2 => match y {
+                    0..=3 => Instruction::JumpConditionalImmediate(JumpCondition::from_u8(y), nn),
+                    4 => Instruction::LoadIOIndirectCA,
+                    5 => Instruction::LoadIndirectImmediate16A(nn),
+                    6 => Instruction::LoadIOAIndirectC,
+                    _ => Instruction::LoadAIndirectImmediate16(nn),
+                },
+                3 => match y {
+                    0 => Instruction::JumpImmediate(nn),
+                    6 => Instruction::DI,
+                    7 => Instruction::EI,
+                    _ => return None,
+                },
+                4 => match y {
+                    0..=3 => Instruction::CallConditionalImmediate(JumpCondition::from_u8(y), nn),
+                    _ => return None,
+                },
+                5 => match (q, p) {
+                    (0, p) => Instruction::Push(Register16::from_byte_af(p)),
+                    (_, 0) => Instruction::CallImmediate(nn),
+                    _ => return None,
+                },
+                6 => Instruction::AluImmediate(ArithmeticOperation::from_u8(y), n),
+                _ => Instruction::Reset(ResetVector::from_u8(y)),
+            },
+        };
+
+        Some(instruction)
+    }
+
+    pub fn decode_cb(opcode: u8) -> Instruction {
+        let y = (opcode & 0b00111000) >> 3;
+        let register = CommonRegister::from_u8(opcode & 0b00000111);
+        match opcode >> 6 {
+            0 => Instruction::RotateShiftRegister(RotationShiftOperation::from_u8(y), register),
+            1 => Instruction::BitRegister(y, register),
+            2 => Instruction::ResRegister(y, register),
+            _ => Instruction::SetRegister(y, register),
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::LoadIOAIndirectImmediate8(_)
+            | Instruction::LoadIOIndirectImmediate8A(_) => "LDH",
+            Instruction::LoadRegisterRegister(_, _)
+            | Instruction::LoadRegisterImmediate8(_, _)
+            | Instruction::LoadAIndirectRegister(_)
+            | Instruction::LoadAIndirectImmediate16(_)
+            | Instruction::LoadIndirectRegisterA(_)
+            | Instruction::LoadIndirectImmediate16A(_)
+            | Instruction::LoadIOIndirectCA
+            | Instruction::LoadIOAIndirectC
+            | Instruction::LoadAIncrementHLIndirect
+            | Instruction::LoadIncrementHLIndirectA
+            | Instruction::LoadADecrementHLIndirect
+            | Instruction::LoadDecrementHLIndirectA
+            | Instruction::LoadRegisterImmediate16(_, _)
+            | Instruction::LoadIndirectImmediate16SP(_)
+            | Instruction::LoadSPHL
+            | Instruction::LoadHLSPImmediate(_) => "LD",
+            Instruction::Push(_) => "PUSH",
+            Instruction::Pop(_) => "POP",
+            Instruction::AluRegister(op, _) | Instruction::AluImmediate(op, _) => op.mnemonic(),
+            Instruction::IncRegister8(_) | Instruction::IncRegister16(_) => "INC",
+            Instruction::DecRegister8(_) | Instruction::DecRegister16(_) => "DEC",
+            Instruction::DecimalAdjust => "DAA",
+            Instruction::Complement => "CPL",
+            Instruction::AddHLRegister(_) | Instruction::AddSPImmediate(_) => "ADD",
+            Instruction::RotateALeft => "RLCA",
+            Instruction::RotateALeftThroughCarry => "RLA",
+            Instruction::RotateARight => "RRCA",
+            Instruction::RotateARightThroughCarry => "RRA",
+            Instruction::RotateShiftRegister(op, _) => op.mnemonic(),
+            Instruction::BitRegister(_, _) => "BIT",
+            Instruction::SetRegister(_, _) => "SET",
+            Instruction::ResRegister(_, _) => "RES",
+            Instruction::Ccf => "CCF",
+            Instruction::Scf => "SCF",
+            Instruction::Nop => "NOP",
+            Instruction::Halt => "HALT",
+            Instruction::Stop => "STOP",
+            Instruction::DI => "DI",
+            Instruction::EI => "EI",
+            Instruction::JumpImmediate(_)
+            | Instruction::JumpHL
+            | Instruction::JumpConditionalImmediate(_, _) => "JP",
+            Instruction::JumpRelative(_) | Instruction::JumpConditionalRelative(_, _) => "JR",
+            Instruction::CallImmediate(_) | Instruction::CallConditionalImmediate(_, _) => "CALL",
+            Instruction::Return | Instruction::ReturnConditional(_) => "RET",
+            Instruction::ReturnFromInterrupt => "RETI",
+            Instruction::Reset(_) => "RST",
+        }
+    }
+
+    // Decodes the instruction at the start of `bytes` with its immediates, and its length in bytes.
+    // None for illegal opcodes or when `bytes` ends in the middle of the instruction.
+    pub fn disassemble(bytes: &[u8]) -> Option<(Instruction, usize)> {
+        let opcode = *bytes.first()?;
+        if opcode == 0xCB {
+            return Some((Instruction::decode_cb(*bytes.get(1)?), 2));
+        }
+        let instruction = Instruction::decode(opcode)?;
+        let n = || bytes.get(1).map(|b| Immediate8(*b));
+        let nn = || {
+            Some(Immediate16(u16::from_le_bytes([
+                *bytes.get(1)?,
+                *bytes.get(2)?,
+            ])))
+        };
+        let instruction = match instruction {
+            Instruction::LoadRegisterImmediate8(r, _) => {
+                Instruction::LoadRegisterImmediate8(r, n()?)
+            }
+            Instruction::LoadAIndirectImmediate16(_) => {
+                Instruction::LoadAIndirectImmediate16(nn()?)
+            }
+            Instruction::LoadIndirectImmediate16A(_) => {
+                Instruction::LoadIndirectImmediate16A(nn()?)
+            }
+            Instruction::LoadIOAIndirectImmediate8(_) => {
+                Instruction::LoadIOAIndirectImmediate8(n()?)
+            }
+            Instruction::LoadIOIndirectImmediate8A(_) => {
+                Instruction::LoadIOIndirectImmediate8A(n()?)
+            }
+            Instruction::LoadRegisterImmediate16(r, _) => {
+                Instruction::LoadRegisterImmediate16(r, nn()?)
+            }
+            Instruction::LoadIndirectImmediate16SP(_) => {
+                Instruction::LoadIndirectImmediate16SP(nn()?)
+            }
+            Instruction::AluImmediate(op, _) => Instruction::AluImmediate(op, n()?),
+            Instruction::AddSPImmediate(_) => Instruction::AddSPImmediate(n()?),
+            Instruction::LoadHLSPImmediate(_) => Instruction::LoadHLSPImmediate(n()?),
+            Instruction::JumpImmediate(_) => Instruction::JumpImmediate(nn()?),
+            Instruction::JumpConditionalImmediate(cc, _) => {
+                Instruction::JumpConditionalImmediate(cc, nn()?)
+            }
+            Instruction::JumpRelative(_) => Instruction::JumpRelative(n()?),
+            Instruction::JumpConditionalRelative(cc, _) => {
+                Instruction::JumpConditionalRelative(cc, n()?)
+            }
+            Instruction::CallImmediate(_) => Instruction::CallImmediate(nn()?),
+            Instruction::CallConditionalImmediate(cc, _) => {
+                Instruction::CallConditionalImmediate(cc, nn()?)
+            }
+            i => return Some((i, 1)),
+        };
+        let length = match instruction {
+            Instruction::LoadAIndirectImmediate16(_)
+            | Instruction::LoadIndirectImmediate16A(_)
+            | Instruction::LoadRegisterImmediate16(_, _)
+            | Instruction::LoadIndirectImmediate16SP(_)
+            | Instruction::JumpImmediate(_)
+            | Instruction::JumpConditionalImmediate(_, _)
+            | Instruction::CallImmediate(_)
+            | Instruction::CallConditionalImmediate(_, _) => 3,
+            _ => 2,
+        };
+        Some((instruction, length))
+    }
+
+    pub fn all_opcodes() -> [Option<&'static str>; 256] {
+        let mut table = [None; 256];
+        for (opcode, entry) in table.iter_mut().enumerate() {
+            *entry = match opcode {
+                0xCB => Some("PREFIX CB"),
+                _ => Instruction::decode(opcode as u8).map(|i| i.mnemonic()),
+            };
+        }
+        table
+    }
+
+    pub fn all_cb_opcodes() -> [&'static str; 256] {
+        let mut table = [""; 256];
+        for (opcode, entry) in table.iter_mut().enumerate() {
+            *entry = Instruction::decode_cb(opcode as u8).mnemonic();
+        }
+        table
+    }
+}
+
+impl Instruction {
+    // Relative jumps also show where they land when the instruction's own address is known
+    pub fn display_at(&self, address: u16) -> String {
+        match self {
+            Instruction::JumpRelative(e) | Instruction::JumpConditionalRelative(_, e) => {
+                format!("{} -> ${:04X}", self, e.relative_target(address))
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
 impl std::fmt::Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Instruction::JumpRelative(e) => write!(f, "JumpRelative({:+})", e.offset()),
+            Instruction::JumpConditionalRelative(cc, e) => {
+                write!(f, "JumpConditionalRelative({}, {:+})", cc, e.offset())
+            }
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -142,6 +551,19 @@ pub enum ArithmeticOperation {
 }
 
 impl ArithmeticOperation {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::AddA => "ADD",
+            Self::AdcA => "ADC",
+            Self::Sub => "SUB",
+            Self::SbcA => "SBC",
+            Self::And => "AND",
+            Self::Xor => "XOR",
+            Self::Or => "OR",
+            Self::Cp => "CP",
+        }
+    }
+
     pub fn from_u8(b: u8) -> Self {
         assert!(b <= 7);
         match b {
@@ -171,6 +593,19 @@ pub enum RotationShiftOperation {
 }
 
 impl RotationShiftOperation {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::Rlc => "RLC",
+            Self::Rrc => "RRC",
+            Self::Rl => "RL",
+            Self::Rr => "RR",
+            Self::Sla => "SLA",
+            Self::Sra => "SRA",
+            Self::Swap => "SWAP",
+            Self::Srl => "SRL",
+        }
+    }
+
     pub fn from_u8(b: u8) -> Self {
         assert!(b <= 7);
         match b {
@@ -245,3 +680,79 @@ impl std::fmt::Display for ResetVector {
         write!(f, "{}", *self as u8)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassemble() {
+        assert_eq!(
+            Instruction::disassemble(&[0xC3, 0x50, 0x01]),
+            Some((Instruction::JumpImmediate(Immediate16(0x0150)), 3))
+        );
+        assert_eq!(
+            Instruction::disassemble(&[0x3E, 0x12, 0xFF]),
+            Some((
+                Instruction::LoadRegisterImmediate8(
+                    CommonRegister::Register8(Register8::A),
+                    Immediate8(0x12)
+                ),
+                2
+            ))
+        );
+        assert_eq!(
+            Instruction::disassemble(&[0xCB, 0x37]),
+            Some((
+                Instruction::RotateShiftRegister(
+                    RotationShiftOperation::Swap,
+                    CommonRegister::Register8(Register8::A)
+                ),
+                2
+            ))
+        );
+        assert_eq!(
+            Instruction::disassemble(&[0x00]),
+            Some((Instruction::Nop, 1))
+        );
+        assert_eq!(Instruction::disassemble(&[0xFA, 0x00]), None);
+        assert_eq!(Instruction::disassemble(&[0xD3]), None);
+    }
+
+    #[test]
+    fn display_operands() {
+        assert_eq!(Immediate8(0x0A).to_string(), "$0A");
+        assert_eq!(Immediate16(0x0150).to_string(), "$0150");
+        assert_eq!(Immediate16(0x0150).value(), 0x0150);
+
+        let jr = Instruction::JumpRelative(Immediate8(0xFD));
+        assert_eq!(jr.to_string(), "JumpRelative(-3)");
+        assert_eq!(jr.display_at(0x0150), "JumpRelative(-3) -> $014F");
+        let jr_nz = Instruction::JumpConditionalRelative(JumpCondition::NZ, Immediate8(0x05));
+        assert_eq!(
+            jr_nz.display_at(0x0150),
+            "JumpConditionalRelative(NZ, +5) -> $0157"
+        );
+    }
+
+    #[test]
+    fn opcode_table() {
+        let table = Instruction::all_opcodes();
+        let illegal = [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ];
+        for opcode in illegal {
+            assert_eq!(table[opcode], None, "opcode {:#04x}", opcode);
+        }
+        assert_eq!(table.iter().filter(|m| m.is_none()).count(), illegal.len());
+        assert_eq!(table[0x00], Some("NOP"));
+        assert_eq!(table[0x76], Some("HALT"));
+        assert_eq!(table[0xE0], Some("LDH"));
+        assert_eq!(table[0xCB], Some("PREFIX CB"));
+
+        let cb_table = Instruction::all_cb_opcodes();
+        assert_eq!(cb_table[0x37], "SWAP");
+        assert_eq!(cb_table[0x7E], "BIT");
+        assert_eq!(cb_table[0xFF], "SET");
+    }
+}
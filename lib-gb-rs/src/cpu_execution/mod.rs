@@ -1,7 +1,7 @@
 use instructions::Instruction;
 use thiserror::Error;
 
-use crate::components::cpu::{Cpu, Flags, Register16, Register8, State};
+use crate::components::cpu::{Cpu, CpuState, Flags, Register16, Register8};
 use crate::cpu_execution::instructions::{Immediate16, Immediate8, JumpCondition};
 use crate::{
     ArithmeticOperation, ClockContext, CommonRegister, EventContext, ExecutionEvent,
@@ -13,6 +13,8 @@ pub mod instructions;
 mod tests_instructions;
 #[cfg(test)]
 mod tests_interrupt;
+#[cfg(test)]
+mod tests_timing;
 
 pub fn get_first_opcode<C: MemoryContext>(cpu: &mut Cpu, context: &mut C) -> u8 {
     Execution { cpu, context }.get_first_opcode()
@@ -53,22 +55,21 @@ impl<'a, C: MemoryContext> Execution<'a, C> {
 }
 
 /*
-TODO
-Currently 16-bit reads tick after each 8bit write, but the value in the register isn't updated until after both.
-Does that matter? Is that observable?
+16-bit registers are only updated after both bytes are read or written, each byte access ticks on its own.
+Memory sees the hardware order: LD (nn), SP writes the LSB first, pushes write the MSB first at SP-1.
  */
 impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext> Execution<'a, C> {
     pub fn handle_next(
         &mut self,
         next_operation: NextOperation,
     ) -> Result<NextOperation, ExecutionError> {
-        if self.cpu.state() == State::Running {
-            match next_operation {
+        match self.cpu.state() {
+            CpuState::Running => match next_operation {
                 NextOperation::Opcode(opcode) => self.decode_execute_fetch(opcode),
                 NextOperation::StartInterruptRoutine => Ok(self.start_interrupt_routine()),
-            }
-        } else {
-            Ok(self.halted(next_operation))
+            },
+            CpuState::Halted => Ok(self.halted(next_operation)),
+            CpuState::Stopped => Ok(self.stopped(next_operation)),
         }
     }
 
@@ -77,7 +78,7 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
         self.context.tick_4();
 
         if self.context.should_cancel_halt() {
-            self.cpu.set_state(State::Running);
+            self.cpu.set_state(CpuState::Running);
             if self.context.should_start_interrupt_routine() {
                 NextOperation::StartInterruptRoutine
             } else {
@@ -89,6 +90,16 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
         }
     }
 
+    fn stopped(&mut self, original_next_op: NextOperation) -> NextOperation {
+        self.context.push_event(ExecutionEvent::Stopped);
+        self.context.tick_4();
+
+        if self.context.should_cancel_stop() {
+            self.cpu.set_state(CpuState::Running);
+        }
+        original_next_op
+    }
+
     fn start_interrupt_routine(&mut self) -> NextOperation {
         self.context
             .push_event(ExecutionEvent::InterruptRoutineStarted);
@@ -115,11 +126,13 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
        * Any reads at PC also increment and tick.
     */
     fn decode_execute_fetch(&mut self, opcode: u8) -> Result<NextOperation, ExecutionError> {
+        let start_cycles = self.context.elapsed_cycles();
         let x = (opcode & 0b11000000) >> 6;
         let y = (opcode & 0b00111000) >> 3;
         let z = opcode & 0b00000111;
         let p = (y & 0b110) >> 1;
         let q = y & 0b1;
+        self.context.opcode_executed(opcode, false);
         let instruction: Instruction = match x {
             0 => self.x_is_0_tree(y, z, p, q),
             1 => {
@@ -139,6 +152,13 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
             3 => self.x_is_3_tree(opcode, y, z, p, q)?,
             _ => return Err(ExecutionError::InvalidOpcode(opcode)),
         };
+        debug_assert!(
+            opcode == 0xCB
+                || Instruction::decode(opcode).map(|i| i.mnemonic())
+                    == Some(instruction.mnemonic()),
+            "Decoder mismatch for {:#04x}",
+            opcode
+        );
         self.context
             .push_event(ExecutionEvent::InstructionExecuted {
                 opcode: HexByte(opcode),
@@ -147,10 +167,36 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
                 cpu: self.cpu.clone(),
             });
 
-        if self.context.should_start_interrupt_routine() {
-            Ok(NextOperation::StartInterruptRoutine)
+        let next_operation = if self.context.should_start_interrupt_routine() {
+            NextOperation::StartInterruptRoutine
         } else {
-            Ok(NextOperation::Opcode(self.read_byte_at_pc()))
+            NextOperation::Opcode(self.read_byte_at_pc())
+        };
+
+        debug_assert_eq!(
+            self.context.elapsed_cycles() - start_cycles,
+            self.expected_cycles(&instruction, next_operation),
+            "Cycle count mismatch for {}",
+            instruction
+        );
+
+        Ok(next_operation)
+    }
+
+    // The opcode of this instruction was fetched by the previous operation, so the cycles
+    // spent here are the instruction's own minus that fetch, plus the fetch of the next opcode.
+    fn expected_cycles(&self, instruction: &Instruction, next_operation: NextOperation) -> u64 {
+        let taken = match *instruction {
+            Instruction::JumpConditionalImmediate(cc, _)
+            | Instruction::JumpConditionalRelative(cc, _)
+            | Instruction::CallConditionalImmediate(cc, _)
+            | Instruction::ReturnConditional(cc) => self.should_jump(cc),
+            _ => true,
+        };
+        let cycles = instruction.cycles().for_branch(taken) as u64;
+        match next_operation {
+            NextOperation::Opcode(_) => cycles,
+            NextOperation::StartInterruptRoutine => cycles - 4,
         }
     }
 
@@ -159,7 +205,7 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
             0 => match y {
                 0 => self.noop(),
                 1 => self.ld_inn_sp(),
-                2 => panic!("STOP"),
+                2 => self.stop(),
                 3 => self.jr(),
                 y => {
                     let cc = JumpCondition::from_u8(y - 4);
@@ -300,10 +346,11 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
 
     fn cb_prefix(&mut self) -> Instruction {
         let opcode = self.read_byte_at_pc();
+        self.context.opcode_executed(opcode, true);
         let x = (opcode & 0b11000000) >> 6;
         let y = (opcode & 0b00111000) >> 3;
         let z = opcode & 0b00000111;
-        match x {
+        let instruction = match x {
             0 => self.rotate_shift(
                 RotationShiftOperation::from_u8(y),
                 CommonRegister::from_u8(z),
@@ -312,7 +359,9 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
             2 => self.res(y, CommonRegister::from_u8(z)),
             3 => self.set(y, CommonRegister::from_u8(z)),
             _ => unreachable!(),
-        }
+        };
+        debug_assert_eq!(instruction, Instruction::decode_cb(opcode));
+        instruction
     }
 
     fn read_byte_at(&mut self, addr: u16) -> u8 {
@@ -567,10 +616,19 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
     }
 
     fn halt(&mut self) -> Instruction {
-        self.cpu.set_state(State::Halted);
+        self.cpu.set_state(CpuState::Halted);
 
         Instruction::Halt
     }
+
+    fn stop(&mut self) -> Instruction {
+        // With a selected button already held, STOP doesn't stop at all
+        if !self.context.should_cancel_stop() {
+            self.cpu.set_state(CpuState::Stopped);
+        }
+
+        Instruction::Stop
+    }
     fn ld_r_r(&mut self, target: CommonRegister, source: CommonRegister) -> Instruction {
         debug_assert!(target != CommonRegister::HLIndirect || source != CommonRegister::HLIndirect);
         if target == CommonRegister::Register8(Register8::B)
@@ -935,8 +993,9 @@ impl<'a, C: MemoryContext + EventContext + ClockContext + HandleInterruptContext
     fn push(&mut self, register: Register16) -> Instruction {
         let sp = self.cpu.read_register16(Register16::SP);
         self.context.tick_4();
-        let w = self.cpu.read_register16(register);
-        self.write_word_to(sp.wrapping_sub(2), w);
+        let [lsb, msb] = self.cpu.read_register16(register).to_le_bytes();
+        self.write_byte_to(sp.wrapping_sub(1), msb);
+        self.write_byte_to(sp.wrapping_sub(2), lsb);
         self.cpu
             .write_register16(Register16::SP, sp.wrapping_sub(2));
 
@@ -8,11 +8,20 @@ use crate::{
 
 const FULL_ADDRESS_SPACE: usize = 64 * KIB;
 
+// A single tick or memory access, for tests that check the exact interleaving
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Access {
+    Tick,
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
 #[derive(Debug)]
 pub struct InstructionTestContext {
     pub cycles: usize,
     pub mem: [u8; FULL_ADDRESS_SPACE],
     pub instruction: Option<Instruction>,
+    pub log: Vec<Access>,
 }
 
 impl Default for InstructionTestContext {
@@ -21,6 +30,7 @@ impl Default for InstructionTestContext {
             cycles: 0,
             mem: [0; FULL_ADDRESS_SPACE],
             instruction: None,
+            log: Vec::new(),
         }
     }
 }
@@ -33,11 +43,14 @@ impl InstructionTestContext {
 
 impl MemoryContext for InstructionTestContext {
     fn read(&mut self, addr: u16) -> u8 {
-        self.mem[addr as usize]
+        let value = self.mem[addr as usize];
+        self.log.push(Access::Read(addr, value));
+        value
     }
 
     fn write(&mut self, addr: u16, value: u8) {
-        self.mem[addr as usize] = value
+        self.mem[addr as usize] = value;
+        self.log.push(Access::Write(addr, value));
     }
 }
 
@@ -51,8 +64,13 @@ impl EventContext for InstructionTestContext {
 
 impl ClockContext for InstructionTestContext {
     fn tick(&mut self) {
+        self.log.push(Access::Tick);
         self.cycles += 1;
     }
+
+    fn elapsed_cycles(&self) -> u64 {
+        self.cycles as u64
+    }
 }
 
 impl InterruptContext for InstructionTestContext {
@@ -74,6 +92,10 @@ impl HandleInterruptContext for InstructionTestContext {
         false
     }
 
+    fn should_cancel_stop(&self) -> bool {
+        false
+    }
+
     fn schedule_ime_enable(&mut self) {}
 
     fn enable_interrupts(&mut self) {}
@@ -81,6 +103,31 @@ impl HandleInterruptContext for InstructionTestContext {
     fn disable_interrupts(&mut self) {}
 }
 
+// Runs the instruction at the start of `program` with the CPU and memory from `setup`. The log and
+// cycles don't include the first opcode fetch, only the fetch of the next opcode at the end.
+pub fn logged_execution(
+    program: &[u8],
+    setup: impl FnOnce(&mut Cpu, &mut InstructionTestContext),
+) -> (InstructionTestContext, Cpu, NextOperation) {
+    let mut cpu = Cpu::default();
+    let mut context = InstructionTestContext::default();
+    context.mem[..program.len()].copy_from_slice(program);
+    setup(&mut cpu, &mut context);
+
+    let opcode = get_first_opcode(&mut cpu, &mut context);
+    context.log.clear();
+    context.reset_cycles();
+
+    let next_operation = Execution {
+        cpu: &mut cpu,
+        context: &mut context,
+    }
+    .decode_execute_fetch(opcode)
+    .unwrap();
+
+    (context, cpu, next_operation)
+}
+
 #[test]
 fn noop() {
     let mut cpu = Cpu::default();
@@ -459,6 +506,25 @@ fn push_pop() {
     assert_eq!(next_operation, NextOperation::Opcode(0xFF));
 }
 
+#[test]
+fn pop_af_masks_flags() {
+    // POP AF
+    let (_, cpu, _) = logged_execution(&[0xF1], |cpu, context| {
+        cpu.write_register16(Register16::SP, 0x3FFE);
+        context.mem[0x3FFE] = 0xFF;
+        context.mem[0x3FFF] = 0x12;
+    });
+    assert_eq!(cpu.read_register16(Register16::AF), 0x12F0);
+
+    // PUSH AF with the CPU from above
+    let (context, _, _) = logged_execution(&[0xF5], |c, _| {
+        *c = cpu;
+        c.write_register16(Register16::PC, 0x0000);
+    });
+    assert_eq!(context.mem[0x3FFE], 0xF0);
+    assert_eq!(context.mem[0x3FFF], 0x12);
+}
+
 #[test]
 fn add_8bit_carry() {
     let mut cpu = Cpu::default();
@@ -666,3 +732,141 @@ fn add_i8_to_u16_test() {
     let b: u16 = 5050;
     assert_eq!(add_i8_to_u16(a, b), 5000);
 }
+
+#[test]
+fn inc_dec_preserve_carry() {
+    for carry in [Flags::C, Flags::empty()] {
+        let mut cpu = Cpu::default();
+        cpu.write_register8(Register8::B, 0xFF);
+        cpu.modify_flags(|f| *f = carry);
+        let mut context = InstructionTestContext::default();
+        // INC B, DEC B
+        context.mem[0] = 0x04;
+        context.mem[1] = 0x05;
+        context.mem[2] = 0xFF;
+
+        let opcode = get_first_opcode(&mut cpu, &mut context);
+        let next_operation = Execution {
+            cpu: &mut cpu,
+            context: &mut context,
+        }
+        .decode_execute_fetch(opcode)
+        .unwrap();
+
+        assert_eq!(
+            context.instruction.unwrap(),
+            Instruction::IncRegister8(CommonRegister::Register8(Register8::B))
+        );
+        assert_eq!(cpu.read_register8(Register8::B), 0x00);
+        assert_eq!(cpu.flags(), Flags::Z | Flags::H | carry);
+
+        let opcode = match next_operation {
+            NextOperation::Opcode(opcode) => opcode,
+            NextOperation::StartInterruptRoutine => unreachable!(),
+        };
+        Execution {
+            cpu: &mut cpu,
+            context: &mut context,
+        }
+        .decode_execute_fetch(opcode)
+        .unwrap();
+
+        assert_eq!(
+            context.instruction.unwrap(),
+            Instruction::DecRegister8(CommonRegister::Register8(Register8::B))
+        );
+        assert_eq!(cpu.read_register8(Register8::B), 0xFF);
+        assert_eq!(cpu.flags(), Flags::N | Flags::H | carry);
+    }
+}
+
+// Runs one ALU instruction with B as the operand, returns A and the flags after
+fn alu_with_b(opcode: u8, a: u8, b: u8, flags: Flags) -> (u8, Flags) {
+    let (_, cpu, _) = logged_execution(&[opcode], |cpu, _| {
+        cpu.write_register8(Register8::A, a);
+        cpu.write_register8(Register8::B, b);
+        cpu.modify_flags(|f| *f = flags);
+    });
+    (cpu.read_register8(Register8::A), cpu.flags())
+}
+
+#[test]
+fn sub_half_carry() {
+    // SUB B borrowing from bit 4 only
+    assert_eq!(
+        alu_with_b(0x90, 0x10, 0x01, Flags::empty()),
+        (0x0F, Flags::N | Flags::H)
+    );
+    assert_eq!(
+        alu_with_b(0x90, 0x1F, 0x0F, Flags::empty()),
+        (0x10, Flags::N)
+    );
+    // SBC A, B where only the carry causes the nibble borrow
+    assert_eq!(
+        alu_with_b(0x98, 0x1F, 0x0F, Flags::C),
+        (0x0F, Flags::N | Flags::H)
+    );
+    assert_eq!(
+        alu_with_b(0x98, 0x00, 0xFF, Flags::C),
+        (0x00, Flags::Z | Flags::N | Flags::H | Flags::C)
+    );
+}
+
+#[test]
+fn cp_flags() {
+    // CP B leaves A alone but sets the flags like SUB
+    assert_eq!(
+        alu_with_b(0xB8, 0x10, 0x01, Flags::empty()),
+        (0x10, Flags::N | Flags::H)
+    );
+    assert_eq!(
+        alu_with_b(0xB8, 0x42, 0x42, Flags::H | Flags::C),
+        (0x42, Flags::Z | Flags::N)
+    );
+    assert_eq!(
+        alu_with_b(0xB8, 0x20, 0x30, Flags::empty()),
+        (0x20, Flags::N | Flags::C)
+    );
+}
+
+// Runs ADD HL, SP, returns HL, the flags and the cycles taken
+fn add_hl_sp(hl: u16, sp: u16, flags: Flags) -> (u16, Flags, usize) {
+    let (context, cpu, _) = logged_execution(&[0x39], |cpu, _| {
+        cpu.write_register16(Register16::HL, hl);
+        cpu.write_register16(Register16::SP, sp);
+        cpu.modify_flags(|f| *f = flags);
+    });
+
+    assert_eq!(
+        context.instruction.unwrap(),
+        Instruction::AddHLRegister(Register16::SP)
+    );
+    (
+        cpu.read_register16(Register16::HL),
+        cpu.flags(),
+        context.cycles,
+    )
+}
+
+#[test]
+fn add_hl_sp_flags() {
+    // Half carry out of bit 11, Z is preserved
+    assert_eq!(
+        add_hl_sp(0x0FFF, 0x0001, Flags::Z | Flags::N),
+        (0x1000, Flags::Z | Flags::H, 8)
+    );
+    // Carry out of bit 15 without a half carry, even though the result is zero
+    assert_eq!(
+        add_hl_sp(0x8000, 0x8000, Flags::empty()),
+        (0x0000, Flags::C, 8)
+    );
+    // A carry out of bit 7 alone sets neither
+    assert_eq!(
+        add_hl_sp(0x00FF, 0x0001, Flags::empty()),
+        (0x0100, Flags::empty(), 8)
+    );
+    assert_eq!(
+        add_hl_sp(0xFFFF, 0xFFFF, Flags::empty()),
+        (0xFFFE, Flags::H | Flags::C, 8)
+    );
+}
@@ -43,6 +43,10 @@ impl ClockContext for InterruptTestContext {
     fn tick(&mut self) {
         self.cycles += 1;
     }
+
+    fn elapsed_cycles(&self) -> u64 {
+        self.cycles as u64
+    }
 }
 
 impl HandleInterruptContext for InterruptTestContext {
@@ -62,6 +66,10 @@ impl HandleInterruptContext for InterruptTestContext {
         false
     }
 
+    fn should_cancel_stop(&self) -> bool {
+        false
+    }
+
     fn schedule_ime_enable(&mut self) {
         panic!()
     }
@@ -0,0 +1,203 @@
+use super::tests_instructions::{logged_execution, Access};
+use super::*;
+
+// A memory access as the CPU performs it: three ticks, the access, one more tick
+fn m_cycle(access: Access) -> [Access; 5] {
+    [
+        Access::Tick,
+        Access::Tick,
+        Access::Tick,
+        access,
+        Access::Tick,
+    ]
+}
+
+#[test]
+fn ld_a_inn_sequence() {
+    let (context, cpu, next_operation) =
+        logged_execution(&[0xFA, 0x34, 0x12, 0xFF], |_, context| {
+            context.mem[0x1234] = 0x56;
+        });
+
+    let expected: Vec<Access> = [
+        m_cycle(Access::Read(0x0001, 0x34)),
+        m_cycle(Access::Read(0x0002, 0x12)),
+        m_cycle(Access::Read(0x1234, 0x56)),
+        m_cycle(Access::Read(0x0003, 0xFF)),
+    ]
+    .concat();
+    assert_eq!(context.log, expected);
+    assert_eq!(cpu.read_register8(Register8::A), 0x56);
+    assert_eq!(next_operation, NextOperation::Opcode(0xFF));
+}
+
+fn assert_cycles(program: &[u8], flags: Flags, expected: usize) {
+    let (context, _, _) = logged_execution(program, |cpu, _| {
+        cpu.write_register16(Register16::SP, 0xFFFE);
+        cpu.write_register16(Register16::HL, 0xC000);
+        cpu.modify_flags(|f| *f = flags);
+    });
+    assert_eq!(context.cycles, expected, "program {:02x?}", program);
+}
+
+#[test]
+fn instruction_cycles() {
+    let none = Flags::empty();
+    assert_cycles(&[0x00], none, 4);
+    assert_cycles(&[0x36, 0x12], none, 12);
+    assert_cycles(&[0x34], none, 12);
+    assert_cycles(&[0xCB, 0x46], none, 12);
+    assert_cycles(&[0xCB, 0xC6], none, 16);
+    assert_cycles(&[0x08, 0x00, 0xC0], none, 20);
+    assert_cycles(&[0xE8, 0x01], none, 16);
+    assert_cycles(&[0xF8, 0x01], none, 12);
+    assert_cycles(&[0xF9], none, 8);
+    assert_cycles(&[0xC5], none, 16);
+    assert_cycles(&[0xC1], none, 12);
+    assert_cycles(&[0xFF], none, 16);
+    assert_cycles(&[0xD9], none, 16);
+}
+
+#[test]
+fn conditional_instruction_cycles() {
+    let none = Flags::empty();
+    // JP NZ, nn
+    assert_cycles(&[0xC2, 0x00, 0x10], none, 16);
+    assert_cycles(&[0xC2, 0x00, 0x10], Flags::Z, 12);
+    // JR Z, e
+    assert_cycles(&[0x28, 0x05], Flags::Z, 12);
+    assert_cycles(&[0x28, 0x05], none, 8);
+    // CALL C, nn
+    assert_cycles(&[0xDC, 0x00, 0x10], Flags::C, 24);
+    assert_cycles(&[0xDC, 0x00, 0x10], none, 12);
+    // RET NC
+    assert_cycles(&[0xD0], none, 20);
+    assert_cycles(&[0xD0], Flags::C, 8);
+    // RET Z
+    assert_cycles(&[0xC8], Flags::Z, 20);
+    assert_cycles(&[0xC8], none, 8);
+}
+
+#[test]
+fn all_conditions_cycles() {
+    // NZ, Z, NC, C with the flags that take and don't take the branch
+    let conditions = [
+        (Flags::empty(), Flags::Z),
+        (Flags::Z, Flags::empty()),
+        (Flags::empty(), Flags::C),
+        (Flags::C, Flags::empty()),
+    ];
+    // Base opcode, operands, taken and not taken cycles
+    let instructions: [(u8, &[u8], usize, usize); 4] = [
+        (0x20, &[0x05], 12, 8),
+        (0xC2, &[0x00, 0x10], 16, 12),
+        (0xC4, &[0x00, 0x10], 24, 12),
+        (0xC0, &[], 20, 8),
+    ];
+    for (base, operands, taken_cycles, not_taken_cycles) in instructions {
+        for (cc, (taken, not_taken)) in conditions.into_iter().enumerate() {
+            let program = [&[base + 8 * cc as u8], operands].concat();
+            assert_cycles(&program, taken, taken_cycles);
+            assert_cycles(&program, not_taken, not_taken_cycles);
+        }
+    }
+}
+
+fn ret_z_log(flags: Flags) -> (Vec<Access>, u16) {
+    let (context, cpu, _) = logged_execution(&[0xC8, 0x00], |cpu, context| {
+        cpu.write_register16(Register16::SP, 0xC000);
+        cpu.modify_flags(|f| *f = flags);
+        context.mem[0xC000] = 0x34;
+        context.mem[0xC001] = 0x12;
+    });
+    (context.log, cpu.read_register16(Register16::PC))
+}
+
+#[test]
+fn ret_cc_sequence() {
+    // A single internal delay for the condition check, and one more only when taken
+    let internal = [Access::Tick; 4];
+
+    let (log, pc) = ret_z_log(Flags::Z);
+    let expected: Vec<Access> = [
+        &internal[..],
+        &m_cycle(Access::Read(0xC000, 0x34)),
+        &m_cycle(Access::Read(0xC001, 0x12)),
+        &internal,
+        &m_cycle(Access::Read(0x1234, 0x00)),
+    ]
+    .concat();
+    assert_eq!(log, expected);
+    assert_eq!(pc, 0x1235);
+
+    let (log, pc) = ret_z_log(Flags::empty());
+    let expected: Vec<Access> = [&internal[..], &m_cycle(Access::Read(0x0001, 0x00))].concat();
+    assert_eq!(log, expected);
+    assert_eq!(pc, 0x0002);
+}
+
+#[test]
+fn push_sequence() {
+    let (context, cpu, _) = logged_execution(&[0xC5], |cpu, _| {
+        cpu.write_register16(Register16::SP, 0xC010);
+        cpu.write_register16(Register16::BC, 0x1234);
+    });
+
+    let expected: Vec<Access> = [
+        &[Access::Tick; 4][..],
+        &m_cycle(Access::Write(0xC00F, 0x12)),
+        &m_cycle(Access::Write(0xC00E, 0x34)),
+        &m_cycle(Access::Read(0x0001, 0x00)),
+    ]
+    .concat();
+    assert_eq!(context.log, expected);
+    assert_eq!(cpu.read_register16(Register16::SP), 0xC00E);
+}
+
+#[test]
+fn ld_nn_sp_sequence() {
+    let (context, _, _) = logged_execution(&[0x08, 0x00, 0xC0], |cpu, _| {
+        cpu.write_register16(Register16::SP, 0xABCD);
+    });
+
+    let expected: Vec<Access> = [
+        m_cycle(Access::Read(0x0001, 0x00)),
+        m_cycle(Access::Read(0x0002, 0xC0)),
+        m_cycle(Access::Write(0xC000, 0xCD)),
+        m_cycle(Access::Write(0xC001, 0xAB)),
+        m_cycle(Access::Read(0x0003, 0x00)),
+    ]
+    .concat();
+    assert_eq!(context.log, expected);
+}
+
+#[test]
+fn swap_hl_sequence() {
+    // (HL) is the byte after the instruction, so the fetch sees the swapped value
+    let (context, _, _) = logged_execution(&[0xCB, 0x36, 0x12], |cpu, _| {
+        cpu.write_register16(Register16::HL, 0x0002);
+    });
+    let expected: Vec<Access> = [
+        m_cycle(Access::Read(0x0001, 0x36)),
+        m_cycle(Access::Read(0x0002, 0x12)),
+        m_cycle(Access::Write(0x0002, 0x21)),
+        m_cycle(Access::Read(0x0002, 0x21)),
+    ]
+    .concat();
+    assert_eq!(context.log, expected);
+
+    let (context, _, _) = logged_execution(&[0xCB, 0x30], |_, _| {});
+    let expected: Vec<Access> = [
+        m_cycle(Access::Read(0x0001, 0x30)),
+        m_cycle(Access::Read(0x0002, 0x00)),
+    ]
+    .concat();
+    assert_eq!(context.log, expected);
+}
+
+#[test]
+fn swap_cycles() {
+    let none = Flags::empty();
+    assert_cycles(&[0xCB, 0x36], none, 16);
+    assert_cycles(&[0xCB, 0x30], none, 8);
+}
@@ -1,4 +1,4 @@
-use crate::{Buffer, ColorId, Cpu, Instruction, Interrupt, Mode};
+use crate::{Buffer, ColorId, Cpu, Instruction, Interrupt, Mode, PaletteKind};
 
 pub struct HexWord(pub u16);
 
@@ -28,6 +28,71 @@ impl std::fmt::Display for HexByte {
     }
 }
 
+pub fn label(address: u16) -> Option<&'static str> {
+    let label = match address {
+        0xFF00 => "JOYP",
+        0xFF01 => "SB",
+        0xFF02 => "SC",
+        0xFF04 => "DIV",
+        0xFF05 => "TIMA",
+        0xFF06 => "TMA",
+        0xFF07 => "TAC",
+        0xFF0F => "IF",
+        0xFF10 => "NR10",
+        0xFF11 => "NR11",
+        0xFF12 => "NR12",
+        0xFF13 => "NR13",
+        0xFF14 => "NR14",
+        0xFF16 => "NR21",
+        0xFF17 => "NR22",
+        0xFF18 => "NR23",
+        0xFF19 => "NR24",
+        0xFF1A => "NR30",
+        0xFF1B => "NR31",
+        0xFF1C => "NR32",
+        0xFF1D => "NR33",
+        0xFF1E => "NR34",
+        0xFF20 => "NR41",
+        0xFF21 => "NR42",
+        0xFF22 => "NR43",
+        0xFF23 => "NR44",
+        0xFF24 => "NR50",
+        0xFF25 => "NR51",
+        0xFF26 => "NR52",
+        0xFF40 => "LCDC",
+        0xFF41 => "STAT",
+        0xFF42 => "SCY",
+        0xFF43 => "SCX",
+        0xFF44 => "LY",
+        0xFF45 => "LYC",
+        0xFF46 => "DMA",
+        0xFF47 => "BGP",
+        0xFF48 => "OBP0",
+        0xFF49 => "OBP1",
+        0xFF4A => "WY",
+        0xFF4B => "WX",
+        0xFF4F => "VBK",
+        0xFF50 => "BANK",
+        0xFF70 => "SVBK",
+        0xFFFF => "IE",
+        _ => return None,
+    };
+    Some(label)
+}
+
+fn write_memory_access(
+    f: &mut std::fmt::Formatter<'_>,
+    name: &str,
+    address: &HexWord,
+    value: &HexByte,
+) -> std::fmt::Result {
+    write!(f, "{}{{address: {}, value: {}}}", name, address, value)?;
+    if let Some(label) = label(address.0) {
+        write!(f, " {}={}", label, value)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum ExecutionEvent {
     MemoryRead {
@@ -40,6 +105,8 @@ pub enum ExecutionEvent {
     },
     ReadFromNonMappedAddress(HexWord),
     WriteToNonMappedAddress(HexWord),
+    ExecutingInvalidRegion(HexWord),
+    SelfLoopDetected(HexWord),
     InstructionExecuted {
         opcode: HexByte,
         instruction: Instruction,
@@ -51,13 +118,24 @@ pub enum ExecutionEvent {
     InterruptRoutineFinished(Interrupt),
     SerialOut(HexByte),
     FrameReady(Box<Buffer>),
+    FrameSkipped,
     PpuModeSwitch {
         mode: Mode,
         x: u16,
         y: u8,
     },
     PpuPixelPushed(u8, u8, ColorId),
+    PaletteChanged {
+        which: PaletteKind,
+        value: u8,
+    },
+    // Also reported as MemoryWritten, this is for watching VRAM alone
+    VramWritten {
+        address: HexWord,
+        value: HexByte,
+    },
     Halted,
+    Stopped,
     DebugTrigger,
 }
 
@@ -70,6 +148,10 @@ impl std::fmt::Display for ExecutionEvent {
             Self::WriteToNonMappedAddress(a) => {
                 write!(f, "WriteToNonMappedAddress({})", a)
             }
+            Self::ExecutingInvalidRegion(a) => {
+                write!(f, "ExecutingInvalidRegion({})", a)
+            }
+            Self::SelfLoopDetected(a) => write!(f, "SelfLoopDetected({})", a),
             Self::InstructionExecuted {
                 opcode,
                 instruction,
@@ -85,10 +167,13 @@ impl std::fmt::Display for ExecutionEvent {
             }
             Self::DebugTrigger => write!(f, "DebugTrigger"),
             Self::MemoryRead { address, value } => {
-                write!(f, "MemoryRead{{address: {}, value: {}}}", address, value)
+                write_memory_access(f, "MemoryRead", address, value)
             }
             Self::MemoryWritten { address, value } => {
-                write!(f, "MemoryWritten{{address: {}, value: {}}}", address, value)
+                write_memory_access(f, "MemoryWritten", address, value)
+            }
+            Self::VramWritten { address, value } => {
+                write_memory_access(f, "VramWritten", address, value)
             }
             Self::InterruptRoutineStarted => write!(f, "InterruptRoutineStarted"),
             Self::InterruptRoutineFinished(interrupt) => {
@@ -99,11 +184,43 @@ impl std::fmt::Display for ExecutionEvent {
             }
             Self::SerialOut(b) => write!(f, "SerialOut({})", b),
             Self::FrameReady(_) => write!(f, "FrameReady"),
+            Self::FrameSkipped => write!(f, "FrameSkipped"),
             Self::PpuModeSwitch { mode, x, y } => {
                 write!(f, "PpuModeSwitch{{mode: {:?}, x: {}, y: {}}}", mode, x, y)
             }
             Self::PpuPixelPushed(x, y, c) => write!(f, "PpuPixelPushed({}, {}, {:?})", x, y, c),
+            Self::PaletteChanged { which, value } => {
+                write!(
+                    f,
+                    "PaletteChanged{{which: {:?}, value: {:#04x}}}",
+                    which, value
+                )
+            }
             Self::Halted => write!(f, "Halted"),
+            Self::Stopped => write!(f, "Stopped"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_access_label() {
+        let written = ExecutionEvent::MemoryWritten {
+            address: HexWord(0xFF40),
+            value: HexByte(0x91),
+        };
+        assert_eq!(
+            written.to_string(),
+            "MemoryWritten{address: 0xff40, value: 0x91} LCDC=0x91"
+        );
+
+        let read = ExecutionEvent::MemoryRead {
+            address: HexWord(0xC000),
+            value: HexByte(0x12),
+        };
+        assert_eq!(read.to_string(), "MemoryRead{address: 0xc000, value: 0x12}");
+    }
+}
@@ -1,33 +1,80 @@
 #![feature(bigint_helper_methods)]
 #![feature(slice_flatten)]
 
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::ops::RangeInclusive;
 use std::path::Path;
 use std::{fs, mem};
 
+use components::apu::Apu;
+use components::boot_rom::BootRom;
+use components::cgb_bank_registers::CgbBankRegisters;
 use components::cpu::Cpu;
+use components::dma::OamDma;
 use components::high_ram::HighRam;
-use components::interrupt_controller::{Interrupt, InterruptController};
+use components::interrupt_controller::InterruptController;
+use components::joypad::Joypad;
 use components::serial::Serial;
 use components::timer::Timer;
 use components::wram::WorkRam;
+use thiserror::Error;
 
-pub use crate::components::cartridge::parse_into_cartridge;
-use crate::components::cartridge::Cartridge;
-pub use crate::components::cpu::{Flags, Register16, Register8};
-pub use crate::components::ppu::{Buffer, Color, ColorId};
-use crate::components::ppu::{Mode, Ppu};
+pub use crate::components::cartridge::{
+    parse_into_cartridge, try_parse_into_cartridge, BankingState, CartridgeHeader, CartridgeType,
+    LoadError, MbcKind,
+};
+use crate::components::cartridge::{Cartridge, FlatRamCartridge};
+pub use crate::components::cpu::{CpuState, Flags, Register16, Register8};
+pub use crate::components::interrupt_controller::Interrupt;
+pub use crate::components::joypad::{Button, DpadConflictPolicy};
+use crate::components::ppu::Ppu;
+pub use crate::components::ppu::{
+    Buffer, Color, ColorId, FrameSink, Line, Mode, PaletteKind, PixelSource, PpuRegisters,
+    ScanlineSink, TileMap,
+};
 pub use crate::cpu_execution::instructions::{
-    ArithmeticOperation, CommonRegister, Immediate16, Immediate8, Instruction, ResetVector,
+    ArithmeticOperation, CommonRegister, Cycles, Immediate16, Immediate8, Instruction, ResetVector,
     RotationShiftOperation,
 };
-use crate::cpu_execution::{get_first_opcode, ExecutionError, NextOperation};
-pub use crate::execution_events::{ExecutionEvent, HexByte, HexWord};
+pub use crate::cpu_execution::ExecutionError;
+use crate::cpu_execution::{get_first_opcode, NextOperation};
+pub use crate::execution_events::{label, ExecutionEvent, HexByte, HexWord};
+use crate::save_state::{SaveState, StateReader, StateWriter};
+pub use crate::save_state::{StateError, STATE_VERSION};
 
+#[macro_use]
+mod logging;
 mod components;
 mod cpu_execution;
 mod execution_events;
+mod save_state;
+#[cfg(test)]
+mod tests_gameboy;
 
 const KIB: usize = 1024;
+// 154 lines of 456 dots
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Model {
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+}
+
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        *self == Model::Cgb
+    }
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Self::Dmg
+    }
+}
 
 pub trait MemoryContext {
     fn read(&mut self, addr: u16) -> u8;
@@ -36,11 +83,16 @@ pub trait MemoryContext {
 
 pub trait EventContext {
     fn push_event(&mut self, event: ExecutionEvent);
+
+    // For profiling, `cb_prefixed` opcodes are the byte after 0xCB
+    fn opcode_executed(&mut self, _opcode: u8, _cb_prefixed: bool) {}
 }
 
 pub trait ClockContext {
     fn tick(&mut self);
 
+    fn elapsed_cycles(&self) -> u64;
+
     fn tick_4(&mut self) {
         self.tick();
         self.tick();
@@ -62,6 +114,8 @@ pub trait HandleInterruptContext {
 
     fn should_cancel_halt(&self) -> bool;
 
+    fn should_cancel_stop(&self) -> bool;
+
     fn schedule_ime_enable(&mut self);
 
     fn enable_interrupts(&mut self);
@@ -69,49 +123,230 @@ pub trait HandleInterruptContext {
     fn disable_interrupts(&mut self);
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Component {
+    Ppu,
+    Timer,
+    Serial,
+    InterruptController,
+}
+
+#[derive(Debug, Clone)]
+pub struct OpcodeHistogram {
+    pub opcodes: [u64; 256],
+    pub cb_opcodes: [u64; 256],
+}
+
+impl Default for OpcodeHistogram {
+    fn default() -> Self {
+        Self {
+            opcodes: [0; 256],
+            cb_opcodes: [0; 256],
+        }
+    }
+}
+
+// A `set_button` call, at the cycle it happened
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InputEvent {
+    pub cycle: u64,
+    pub button: Button,
+    pub pressed: bool,
+}
+
+impl InputEvent {
+    pub fn frame(&self) -> u64 {
+        self.cycle / CYCLES_PER_FRAME
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub is_write: bool,
+    // Of the instruction doing the access
+    pub pc: u16,
+}
+
+type MemoryTracer = (RangeInclusive<u16>, Box<dyn FnMut(MemAccess)>);
+
 pub struct GameboyContext {
     clock_counter: u64,
+    boot_rom: BootRom,
     cartridge: Box<dyn Cartridge>,
+    // Header and global checksums, identifying the cartridge in save states
+    cartridge_checksum: [u8; 3],
     wram: WorkRam,
     serial: Serial,
+    joypad: Joypad,
     high_ram: HighRam,
     interrupt_controller: InterruptController,
     timer: Timer,
     ppu: Ppu,
+    apu: Apu,
+    cgb_bank_registers: CgbBankRegisters,
+    dma: OamDma,
     events: Vec<ExecutionEvent>,
+    profile: Option<Box<OpcodeHistogram>>,
+    // LD B, B as the mooneye debug breakpoint
+    debug_trigger: bool,
+    // Reads and writes per address
+    unmapped_accesses: HashMap<u16, (u64, u64)>,
+    unmapped_events: bool,
+    memory_tracer: Option<MemoryTracer>,
+    // Set before each operation, only for the memory tracer
+    current_pc: u16,
+    // Components tick once per `clock_divider` CPU cycles, for timing experiments
+    clock_divider: u32,
+    divider_phase: u32,
 }
 
 impl GameboyContext {
-    pub fn new(cartridge: Box<dyn Cartridge>) -> Self {
+    pub fn new(cartridge: Box<dyn Cartridge>, model: Model) -> Self {
         Self {
             clock_counter: 0,
+            boot_rom: BootRom::default(),
+            cartridge_checksum: header_checksum(cartridge.as_ref()),
             cartridge,
             wram: WorkRam::default(),
             serial: Serial::default(),
+            joypad: Joypad::default(),
             high_ram: HighRam::default(),
             interrupt_controller: InterruptController::default(),
             timer: Timer::default(),
-            ppu: Ppu::default(),
+            ppu: Ppu::new(model),
+            apu: Apu::new(model),
+            cgb_bank_registers: CgbBankRegisters::new(model),
+            dma: OamDma::default(),
             events: Vec::with_capacity(100),
+            profile: None,
+            debug_trigger: true,
+            unmapped_accesses: HashMap::new(),
+            unmapped_events: true,
+            memory_tracer: None,
+            current_pc: 0,
+            clock_divider: 1,
+            divider_phase: 0,
         }
     }
 }
 
-impl MemoryContext for GameboyContext {
-    fn read(&mut self, addr: u16) -> u8 {
-        let result = self
-            .wram
+fn read_state(
+    state: &[u8],
+    cartridge_checksum: [u8; 3],
+    cpu: &mut Cpu,
+    context: &mut GameboyContext,
+) -> Result<NextOperation, StateError> {
+    let mut r = StateReader::new(state, cartridge_checksum)?;
+    cpu.load(&mut r)?;
+    let next_operation = match r.array()? {
+        [0, opcode] => NextOperation::Opcode(opcode),
+        [1, _] => NextOperation::StartInterruptRoutine,
+        _ => return Err(StateError::Invalid("next operation")),
+    };
+    context.load(&mut r)?;
+    Ok(next_operation)
+}
+
+// Only valid for a freshly loaded cartridge: later, bank 0 may be switched out (MBC1 mode 1) or the
+// header overwritten (flat RAM)
+fn header_checksum(cartridge: &dyn Cartridge) -> [u8; 3] {
+    [0x014D, 0x014E, 0x014F].map(|a| cartridge.read(a).unwrap_or(0xFF))
+}
+
+impl SaveState for GameboyContext {
+    fn save(&self, w: &mut StateWriter) {
+        w.u64(self.clock_counter);
+        self.boot_rom.save(w);
+        w.vec(&self.cartridge.banking_snapshot());
+        self.wram.save(w);
+        self.serial.save(w);
+        self.joypad.save(w);
+        self.high_ram.save(w);
+        self.interrupt_controller.save(w);
+        self.timer.save(w);
+        self.ppu.save(w);
+        self.apu.save(w);
+        self.cgb_bank_registers.save(w);
+        self.dma.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.clock_counter = r.u64()?;
+        self.boot_rom.load(r)?;
+        self.cartridge.restore_banking(&r.vec()?);
+        self.wram.load(r)?;
+        self.serial.load(r)?;
+        self.joypad.load(r)?;
+        self.high_ram.load(r)?;
+        self.interrupt_controller.load(r)?;
+        self.timer.load(r)?;
+        self.ppu.load(r)?;
+        self.apu.load(r)?;
+        self.cgb_bank_registers.load(r)?;
+        self.dma.load(r)
+    }
+}
+
+impl GameboyContext {
+    fn read_mapped(&self, addr: u16) -> Option<u8> {
+        self.boot_rom
             .read(addr)
+            .or_else(|| self.wram.read(addr))
             .or_else(|| self.serial.read(addr))
+            .or_else(|| self.joypad.read(addr))
             .or_else(|| self.cartridge.read(addr))
             .or_else(|| self.high_ram.read(addr))
             .or_else(|| self.interrupt_controller.read(addr))
             .or_else(|| self.timer.read(addr))
             .or_else(|| self.ppu.read(addr))
-            .unwrap_or_else(|| {
+            .or_else(|| self.apu.read(addr))
+            .or_else(|| self.cgb_bank_registers.read(addr))
+            .or_else(|| self.dma.read(addr))
+    }
+
+    fn trace_access(&mut self, addr: u16, value: u8, is_write: bool) {
+        if let Some((range, tracer)) = &mut self.memory_tracer {
+            if range.contains(&addr) {
+                tracer(MemAccess {
+                    addr,
+                    value,
+                    is_write,
+                    pc: self.current_pc,
+                });
+            }
+        }
+    }
+
+    // The source is read like the CPU would, so VRAM reads from 0xFF during mode 3
+    fn tick_dma(&mut self) {
+        if let Some((source, index)) = self.dma.tick() {
+            let value = self.read_mapped(source).unwrap_or(0xFF);
+            self.ppu.write_oam_dma(index, value);
+        }
+    }
+}
+
+fn is_oam(addr: u16) -> bool {
+    (0xFE00..0xFEA0).contains(&addr)
+}
+
+impl MemoryContext for GameboyContext {
+    fn read(&mut self, addr: u16) -> u8 {
+        let result = if self.dma.is_active() && is_oam(addr) {
+            Some(0xFF)
+        } else {
+            self.read_mapped(addr)
+        };
+        let result = result.unwrap_or_else(|| {
+            self.unmapped_accesses.entry(addr).or_default().0 += 1;
+            if self.unmapped_events {
                 self.push_event(ExecutionEvent::ReadFromNonMappedAddress(HexWord(addr)));
-                0xFF
-            });
+            }
+            0xFF
+        });
+        self.trace_access(addr, result, false);
         self.push_event(ExecutionEvent::MemoryRead {
             address: HexWord(addr),
             value: HexByte(result),
@@ -120,16 +355,32 @@ impl MemoryContext for GameboyContext {
     }
 
     fn write(&mut self, addr: u16, value: u8) {
+        self.trace_access(addr, value, true);
+        if self.dma.is_active() && is_oam(addr) {
+            self.push_event(ExecutionEvent::MemoryWritten {
+                address: HexWord(addr),
+                value: HexByte(value),
+            });
+            return;
+        }
         self.wram
             .write(addr, value)
             .or_else(|| self.serial.write(addr, value))
+            .or_else(|| self.joypad.write(addr, value))
             .or_else(|| self.cartridge.write(addr, value))
             .or_else(|| self.high_ram.write(addr, value))
             .or_else(|| self.interrupt_controller.write(addr, value))
             .or_else(|| self.timer.write(addr, value))
-            .or_else(|| self.ppu.write(addr, value))
+            .or_else(|| self.ppu.write_with_events(addr, value, &mut self.events))
+            .or_else(|| self.apu.write(addr, value))
+            .or_else(|| self.cgb_bank_registers.write(addr, value))
+            .or_else(|| self.dma.write(addr, value))
+            .or_else(|| self.boot_rom.write(addr, value))
             .unwrap_or_else(|| {
-                self.push_event(ExecutionEvent::ReadFromNonMappedAddress(HexWord(addr)));
+                self.unmapped_accesses.entry(addr).or_default().1 += 1;
+                if self.unmapped_events {
+                    self.push_event(ExecutionEvent::WriteToNonMappedAddress(HexWord(addr)));
+                }
             });
         self.push_event(ExecutionEvent::MemoryWritten {
             address: HexWord(addr),
@@ -146,20 +397,45 @@ impl EventContext for Vec<ExecutionEvent> {
 
 impl EventContext for GameboyContext {
     fn push_event(&mut self, event: ExecutionEvent) {
+        if let ExecutionEvent::DebugTrigger = event {
+            if !self.debug_trigger {
+                return;
+            }
+        }
         self.events.push_event(event)
     }
+
+    fn opcode_executed(&mut self, opcode: u8, cb_prefixed: bool) {
+        if let Some(profile) = &mut self.profile {
+            if cb_prefixed {
+                profile.cb_opcodes[opcode as usize] += 1;
+            } else {
+                profile.opcodes[opcode as usize] += 1;
+            }
+        }
+    }
 }
 
 impl ClockContext for GameboyContext {
     fn tick(&mut self) {
-        self.timer.tick(&mut self.interrupt_controller);
-        self.serial
-            .tick(&mut self.interrupt_controller, &mut self.events);
-        self.ppu
-            .tick(&mut self.interrupt_controller, &mut self.events);
+        self.divider_phase += 1;
+        if self.divider_phase >= self.clock_divider {
+            self.divider_phase = 0;
+            self.timer.tick(&mut self.interrupt_controller);
+            self.serial
+                .tick(&mut self.interrupt_controller, &mut self.events);
+            self.ppu
+                .tick(&mut self.interrupt_controller, &mut self.events);
+            self.apu.tick();
+            self.tick_dma();
+        }
         self.interrupt_controller.tick();
         self.clock_counter += 1;
     }
+
+    fn elapsed_cycles(&self) -> u64 {
+        self.clock_counter
+    }
 }
 
 impl InterruptContext for GameboyContext {
@@ -186,6 +462,10 @@ impl HandleInterruptContext for GameboyContext {
         self.interrupt_controller.should_cancel_halt()
     }
 
+    fn should_cancel_stop(&self) -> bool {
+        self.joypad.any_selected_pressed()
+    }
+
     fn schedule_ime_enable(&mut self) {
         self.interrupt_controller.schedule_ime_enable()
     }
@@ -199,24 +479,156 @@ impl HandleInterruptContext for GameboyContext {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PendingOp {
+    Opcode(u8),
+    StartInterruptRoutine,
+}
+
+impl From<NextOperation> for PendingOp {
+    fn from(next_operation: NextOperation) -> Self {
+        match next_operation {
+            NextOperation::Opcode(opcode) => PendingOp::Opcode(opcode),
+            NextOperation::StartInterruptRoutine => PendingOp::StartInterruptRoutine,
+        }
+    }
+}
+
+// Mooneye test ROMs load the Fibonacci numbers into B, C, D, E, H and L on success
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MooneyeResult {
+    Pass,
+    Fail,
+}
+
+#[derive(Debug, Error)]
+pub enum RunError {
+    #[error("No matching event within {0} cycles")]
+    Timeout(u64),
+    #[error(transparent)]
+    Execution(#[from] ExecutionError),
+}
+
+pub type StepResult = (Vec<ExecutionEvent>, Result<Instruction, ExecutionError>);
+
 pub struct GameBoy {
     cpu: Cpu,
     context: GameboyContext,
     next_operation: NextOperation,
+    serial_printer: Option<Box<dyn Write>>,
+    // Separate from the printer, so turning echo off leaves an attached printer alone
+    serial_echo: Option<Box<dyn Write>>,
+    execution_guard: bool,
+    detect_self_loop: bool,
+    turbo: u32,
+    idle_skip: bool,
+    input_log: Option<Vec<InputEvent>>,
+    input_replay: VecDeque<InputEvent>,
 }
 
 impl GameBoy {
+    pub const CPU_HZ: u64 = 4_194_304;
+    pub const FRAME_RATE: f64 = 59.7275;
+    // Channel 3, the fastest, steps its wave position at most every 2 cycles. There is no sample
+    // output yet, so this is the rate a frontend would resample from.
+    pub const AUDIO_NATIVE_HZ: u64 = Self::CPU_HZ / 2;
+
     pub fn new(cartridge: Box<dyn Cartridge>) -> Self {
-        let mut cpu = Cpu::after_boot_rom();
-        let mut context = GameboyContext::new(cartridge);
+        Self::new_with_model(cartridge, Model::default())
+    }
+
+    pub fn new_with_model(cartridge: Box<dyn Cartridge>, model: Model) -> Self {
+        let mut cpu = Cpu::after_boot_rom_for(model);
+        let mut context = GameboyContext::new(cartridge, model);
         let initial_opcode = get_first_opcode(&mut cpu, &mut context);
         Self {
             cpu,
             context,
             next_operation: NextOperation::Opcode(initial_opcode),
+            serial_printer: None,
+            serial_echo: None,
+            execution_guard: false,
+            detect_self_loop: false,
+            turbo: 1,
+            idle_skip: false,
+            input_log: None,
+            input_replay: VecDeque::new(),
         }
     }
 
+    // Starts from power-on with the boot ROM mapped over the cartridge
+    pub fn with_boot_rom(
+        cartridge: Box<dyn Cartridge>,
+        boot_rom: Vec<u8>,
+        model: Model,
+    ) -> Result<Self, LoadError> {
+        let boot_rom = BootRom::new(boot_rom)?;
+        let mut cpu = Cpu::default();
+        let mut context = GameboyContext::new(cartridge, model);
+        context.boot_rom = boot_rom;
+        context.ppu.write(0xFF40, 0).unwrap();
+        let initial_opcode = get_first_opcode(&mut cpu, &mut context);
+        Ok(Self {
+            cpu,
+            context,
+            next_operation: NextOperation::Opcode(initial_opcode),
+            serial_printer: None,
+            serial_echo: None,
+            execution_guard: false,
+            detect_self_loop: false,
+            turbo: 1,
+            idle_skip: false,
+            input_log: None,
+            input_replay: VecDeque::new(),
+        })
+    }
+
+    // CPU test programs without a cartridge header: flat RAM with the program at 0x0100, the post-boot
+    // CPU and the LCD off
+    pub fn diagnostic(program: Vec<u8>) -> Result<Self, LoadError> {
+        let cartridge = FlatRamCartridge::new(&program, 0x0100)?;
+        let mut gb = Self::new(Box::new(cartridge));
+        gb.context.ppu.write(0xFF40, 0).unwrap();
+        Ok(gb)
+    }
+
+    pub fn from_rom_path<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let rom = fs::read(path)?;
+        Ok(Self::new(try_parse_into_cartridge(rom)?))
+    }
+
+    // Rebuilds the machine from a state attached to a bug report, without needing the ROM's path
+    pub fn from_state_and_rom(state: &[u8], rom: Vec<u8>) -> Result<Self, StateError> {
+        let mut gb = Self::new(try_parse_into_cartridge(rom)?);
+        gb.load_state(state)?;
+        Ok(gb)
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.context.cartridge_checksum);
+        self.cpu.save(&mut w);
+        match self.next_operation {
+            NextOperation::Opcode(opcode) => w.bytes(&[0, opcode]),
+            NextOperation::StartInterruptRoutine => w.bytes(&[1, 0]),
+        }
+        self.context.save(&mut w);
+        w.finish()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let checksum = self.context.cartridge_checksum;
+        // Try a scratch machine first so a bad state doesn't leave this one half loaded. No load
+        // depends on the model or the cartridge, whose banking can't fail to restore.
+        let cartridge = Box::new(FlatRamCartridge::new(&[], 0).unwrap());
+        let mut scratch = GameboyContext::new(cartridge, Model::default());
+        read_state(state, checksum, &mut Cpu::default(), &mut scratch)?;
+
+        self.next_operation = read_state(state, checksum, &mut self.cpu, &mut self.context)?;
+        Ok(())
+    }
+
     pub fn get_elapsed_cycles(&self) -> u64 {
         self.context.clock_counter
     }
@@ -226,21 +638,605 @@ impl GameBoy {
     }
 
     pub fn execute_operation(&mut self) -> (Vec<ExecutionEvent>, Result<(), ExecutionError>) {
+        if self.execution_guard {
+            self.check_execution_region();
+        }
+        if self.detect_self_loop {
+            self.check_self_loop();
+        }
+        if self.context.memory_tracer.is_some() {
+            let pc = self.cpu.read_register16(Register16::PC);
+            self.context.current_pc = match self.next_operation {
+                NextOperation::Opcode(_) => pc.wrapping_sub(1),
+                NextOperation::StartInterruptRoutine => pc,
+            };
+        }
         let res = cpu_execution::handle_next(&mut self.cpu, self.next_operation, &mut self.context)
             .map(|no| self.next_operation = no);
-        (self.take_events(), res)
+        let events = self.take_events();
+        self.print_serial(&events);
+        (events, res)
+    }
+
+    fn print_serial(&mut self, events: &[ExecutionEvent]) {
+        for printer in self.serial_printer.iter_mut().chain(&mut self.serial_echo) {
+            for e in events {
+                if let ExecutionEvent::SerialOut(b) = e {
+                    if let Err(err) = printer.write_all(&[b.0]).and_then(|_| printer.flush()) {
+                        warn!("Failed to print serial output: {}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    // Runs operations until one instruction has executed, including any interrupt dispatch or
+    // halted cycles before it. Doesn't return if nothing ever wakes the CPU.
+    pub fn step_instruction(&mut self) -> StepResult {
+        let mut events = Vec::new();
+        loop {
+            let (new_events, res) = self.execute_operation();
+            events.extend(new_events);
+            if let Err(e) = res {
+                return (events, Err(e));
+            }
+            let instruction = events.iter().rev().find_map(|e| match e {
+                ExecutionEvent::InstructionExecuted { instruction, .. } => Some(*instruction),
+                _ => None,
+            });
+            if let Some(instruction) = instruction {
+                return (events, Ok(instruction));
+            }
+        }
+    }
+
+    // Interrupt dispatches and halted cycles don't count towards `n`
+    pub fn run_instructions(
+        &mut self,
+        n: usize,
+    ) -> (Vec<ExecutionEvent>, Result<(), ExecutionError>) {
+        let mut events = Vec::new();
+        for _ in 0..n {
+            let (new_events, res) = self.step_instruction();
+            events.extend(new_events);
+            if let Err(e) = res {
+                return (events, Err(e));
+            }
+        }
+        (events, Ok(()))
+    }
+
+    // Runs `frames` frames worth of cycles, times the turbo factor
+    pub fn run_frames(&mut self, frames: u32) -> (Vec<ExecutionEvent>, Result<(), ExecutionError>) {
+        let frames = u64::from(frames) * u64::from(self.turbo);
+        let target = self.get_elapsed_cycles() + frames * CYCLES_PER_FRAME;
+        let mut events = Vec::new();
+        while self.get_elapsed_cycles() < target {
+            self.apply_replayed_input();
+            if let Some((value, exit_on_equal)) = self.ly_wait_loop() {
+                let limit = self
+                    .input_replay
+                    .front()
+                    .map_or(target, |input| input.cycle.min(target));
+                self.skip_ly_wait(value, exit_on_equal, limit);
+                let new_events = self.take_events();
+                self.print_serial(&new_events);
+                events.extend(new_events);
+                continue;
+            }
+            let (new_events, res) = self.execute_operation();
+            events.extend(new_events);
+            if res.is_err() {
+                return (events, res);
+            }
+        }
+        (events, Ok(()))
+    }
+
+    // `LDH A, (LY); CP n; JR NZ/Z, -6` with its first opcode already fetched. Returns n and whether
+    // the loop exits once LY equals it.
+    fn ly_wait_loop(&self) -> Option<(u8, bool)> {
+        if !self.idle_skip
+            || self.context.profile.is_some()
+            || self.context.memory_tracer.is_some()
+            || self.cpu.state() != CpuState::Running
+            || self.next_operation != NextOperation::Opcode(0xF0)
+        {
+            return None;
+        }
+        let pc = self.cpu.read_register16(Register16::PC);
+        let bytes = [0, 1, 2, 3, 4].map(|i| self.context.read_mapped(pc.wrapping_add(i)));
+        match bytes {
+            [Some(0x44), Some(0xFE), Some(value), Some(0x20), Some(0xFA)] => Some((value, true)),
+            [Some(0x44), Some(0xFE), Some(value), Some(0x28), Some(0xFA)] => Some((value, false)),
+            _ => None,
+        }
+    }
+
+    // Replays the loop's bus timing without decoding it or emitting CPU events. Hands back to the
+    // interpreter at the first instruction boundary where the loop exits, an interrupt is due or
+    // `target` is reached, with the registers as if every iteration had run.
+    fn skip_ly_wait(&mut self, value: u8, exit_on_equal: bool, target: u64) {
+        let head = self.cpu.read_register16(Register16::PC).wrapping_sub(1);
+        loop {
+            // LDH A, (LY)
+            self.context.tick_4();
+            self.context.tick();
+            self.context.tick();
+            self.context.tick();
+            let ly = self.context.read_mapped(0xFF44).unwrap_or(0xFF);
+            self.context.tick();
+            self.cpu.write_register8(Register8::A, ly);
+            self.cpu
+                .write_register16(Register16::PC, head.wrapping_add(2));
+            if self.finish_skipped_instruction(target) {
+                return;
+            }
+
+            // CP n
+            self.context.tick_4();
+            self.cpu
+                .write_register16(Register16::PC, head.wrapping_add(4));
+            self.cpu.modify_flags(|f| {
+                f.set(Flags::Z, ly == value);
+                f.set(Flags::C, ly < value);
+                f.set(Flags::H, (ly & 0x0F) < (value & 0x0F));
+                f.insert(Flags::N);
+            });
+            if self.finish_skipped_instruction(target) || (ly == value) == exit_on_equal {
+                return;
+            }
+
+            // JR NZ/Z, -6, taken
+            self.context.tick_4();
+            self.context.tick_4();
+            self.cpu.write_register16(Register16::PC, head);
+            if self.finish_skipped_instruction(target) {
+                return;
+            }
+        }
+    }
+
+    // The tail of decode_execute_fetch: start a due interrupt or fetch the next opcode. Returns
+    // whether the interpreter should take over.
+    fn finish_skipped_instruction(&mut self, target: u64) -> bool {
+        if self.context.should_start_interrupt_routine() {
+            self.next_operation = NextOperation::StartInterruptRoutine;
+            return true;
+        }
+        let pc = self.cpu.read_register16(Register16::PC);
+        self.context.tick();
+        self.context.tick();
+        self.context.tick();
+        let opcode = self.context.read_mapped(pc).unwrap_or(0xFF);
+        self.context.tick();
+        self.cpu.increment_pc();
+        self.next_operation = NextOperation::Opcode(opcode);
+        self.get_elapsed_cycles() >= target
+    }
+
+    // Runs until an event matches, returning it. Other events of the operation that produced it are
+    // dropped.
+    pub fn run_until<F: Fn(&ExecutionEvent) -> bool>(
+        &mut self,
+        pred: F,
+        max_cycles: u64,
+    ) -> Result<ExecutionEvent, RunError> {
+        let target = self.get_elapsed_cycles() + max_cycles;
+        while self.get_elapsed_cycles() < target {
+            let (events, res) = self.execute_operation();
+            if let Some(event) = events.into_iter().find(|e| pred(e)) {
+                return Ok(event);
+            }
+            res?;
+        }
+        Err(RunError::Timeout(max_cycles))
+    }
+
+    // Only a hint for the host: run_frames batches this many frames, of which a frontend should
+    // only present the last. Emulation itself is unchanged.
+    pub fn set_turbo(&mut self, factor: u32) {
+        self.turbo = factor.max(1);
+    }
+
+    pub fn turbo(&self) -> u32 {
+        self.turbo
+    }
+
+    // Not like any real hardware: slows every component but the CPU down by `divider`, so cycle
+    // counts and run_frames stay in CPU cycles
+    pub fn set_clock_divider(&mut self, divider: u32) {
+        self.context.clock_divider = divider.max(1);
+        self.context.divider_phase = 0;
+    }
+
+    // Lets run_frames fast-forward through `LDH A, (LY); CP n; JR NZ/Z` polling loops. The end
+    // state is the same as running them, only the CPU's own events for the loop are missing.
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.idle_skip = enabled;
+    }
+
+    pub fn pending_operation(&self) -> PendingOp {
+        self.next_operation.into()
+    }
+
+    // Instructions have no fixed length, so listing backward from PC is a guess: scan forward from up
+    // to 3 bytes per wanted instruction back, and keep the furthest start that lands exactly on the
+    // current instruction. Illegal opcodes end the listing.
+    pub fn disassemble_around(&self, before: usize, after: usize) -> Vec<(u16, Instruction, bool)> {
+        let pc = self.cpu.read_register16(Register16::PC);
+        let current = match self.next_operation {
+            NextOperation::Opcode(_) => pc.wrapping_sub(1),
+            NextOperation::StartInterruptRoutine => pc,
+        };
+
+        let lookback = before * 3;
+        let mut listing = (1..=lookback)
+            .rev()
+            .find_map(|distance| {
+                let mut address = current.wrapping_sub(distance as u16);
+                let mut instructions = Vec::new();
+                let mut remaining = distance;
+                while remaining > 0 {
+                    let (instruction, length) = self.disassemble_at(address)?;
+                    remaining = remaining.checked_sub(length)?;
+                    instructions.push((address, instruction, false));
+                    address = address.wrapping_add(length as u16);
+                }
+                Some(instructions)
+            })
+            .unwrap_or_default();
+        listing.drain(..listing.len().saturating_sub(before));
+
+        let mut address = current;
+        for i in 0..=after {
+            match self.disassemble_at(address) {
+                Some((instruction, length)) => {
+                    listing.push((address, instruction, i == 0));
+                    address = address.wrapping_add(length as u16);
+                }
+                None => break,
+            }
+        }
+        listing
+    }
+
+    fn disassemble_at(&self, address: u16) -> Option<(Instruction, usize)> {
+        let bytes = [0, 1, 2].map(|i| {
+            self.context
+                .read_mapped(address.wrapping_add(i))
+                .unwrap_or(0xFF)
+        });
+        Instruction::disassemble(&bytes)
+    }
+
+    // Only meaningful once the ROM hit its debug trigger
+    pub fn mooneye_result(&self) -> MooneyeResult {
+        let registers = [
+            Register8::B,
+            Register8::C,
+            Register8::D,
+            Register8::E,
+            Register8::H,
+            Register8::L,
+        ]
+        .map(|r| self.cpu.read_register8(r));
+        if registers == MOONEYE_PASS {
+            MooneyeResult::Pass
+        } else {
+            MooneyeResult::Fail
+        }
+    }
+
+    pub fn unmapped_access_stats(&self) -> HashMap<u16, (u64, u64)> {
+        self.context.unmapped_accesses.clone()
+    }
+
+    pub fn clear_unmapped_stats(&mut self) {
+        self.context.unmapped_accesses.clear();
+    }
+
+    // The counts keep going without the events
+    pub fn set_unmapped_events(&mut self, enabled: bool) {
+        self.context.unmapped_events = enabled;
+    }
+
+    // Calls `tracer` for every CPU access within `range`, replacing any previous tracer
+    pub fn trace_memory(
+        &mut self,
+        range: RangeInclusive<u16>,
+        tracer: impl FnMut(MemAccess) + 'static,
+    ) {
+        self.context.memory_tracer = Some((range, Box::new(tracer)));
+    }
+
+    pub fn stop_tracing_memory(&mut self) {
+        self.context.memory_tracer = None;
+    }
+
+    pub fn set_debug_trigger(&mut self, enabled: bool) {
+        self.context.debug_trigger = enabled;
+    }
+
+    pub fn set_execution_guard(&mut self, enabled: bool) {
+        self.execution_guard = enabled;
+    }
+
+    fn check_execution_region(&mut self) {
+        if self.cpu.state() != CpuState::Running {
+            return;
+        }
+        if let NextOperation::Opcode(_) = self.next_operation {
+            // The opcode was already fetched, so PC is one past it
+            let pc = self.cpu.read_register16(Register16::PC).wrapping_sub(1);
+            if !is_executable(pc) {
+                self.context
+                    .push_event(ExecutionEvent::ExecutingInvalidRegion(HexWord(pc)));
+            }
+        }
+    }
+
+    pub fn set_detect_self_loop(&mut self, enabled: bool) {
+        self.detect_self_loop = enabled;
+    }
+
+    // Only unconditional jumps to their own address: nothing but an interrupt gets out of those
+    fn check_self_loop(&mut self) {
+        if self.cpu.state() != CpuState::Running {
+            return;
+        }
+        if let NextOperation::Opcode(opcode) = self.next_operation {
+            let pc = self.cpu.read_register16(Register16::PC).wrapping_sub(1);
+            let operand = |i| self.context.read_mapped(pc.wrapping_add(i));
+            let is_self_loop = match opcode {
+                0x18 => operand(1) == Some(0xFE),
+                0xC3 => operand(1) == Some(pc as u8) && operand(2) == Some((pc >> 8) as u8),
+                _ => false,
+            };
+            if is_self_loop {
+                self.context
+                    .push_event(ExecutionEvent::SelfLoopDetected(HexWord(pc)));
+            }
+        }
+    }
+
+    pub fn write_register8(&mut self, reg: Register8, value: u8) {
+        self.cpu.write_register8(reg, value)
+    }
+
+    pub fn write_register16(&mut self, reg: Register16, value: u16) {
+        self.cpu.write_register16(reg, value);
+        if reg == Register16::PC {
+            let opcode = get_first_opcode(&mut self.cpu, &mut self.context);
+            self.next_operation = NextOperation::Opcode(opcode);
+        }
+    }
+
+    pub fn attach_serial_printer(&mut self, printer: impl Write + 'static) {
+        self.serial_printer = Some(Box::new(printer));
+    }
+
+    pub fn detach_serial_printer(&mut self) {
+        self.serial_printer = None;
+    }
+
+    // Shorthand for running test ROMs: echo serial output to stdout, besides any attached printer
+    pub fn set_serial_echo(&mut self, echo: bool) {
+        if echo {
+            self.set_serial_echo_to(std::io::stdout());
+        } else {
+            self.serial_echo = None;
+        }
+    }
+
+    fn set_serial_echo_to(&mut self, out: impl Write + 'static) {
+        self.serial_echo = Some(Box::new(out));
     }
 
     pub fn cpu(&self) -> &Cpu {
         &self.cpu
     }
 
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    // Like a flashcart switching games under the running program: everything but the cartridge is
+    // kept, including an opcode already fetched from the old one
+    pub fn swap_cartridge(&mut self, cartridge: Box<dyn Cartridge>) {
+        self.context.cartridge_checksum = header_checksum(cartridge.as_ref());
+        self.context.cartridge = cartridge;
+    }
+
+    pub fn banking_state(&self) -> BankingState {
+        BankingState::of(self.context.cartridge.as_ref())
+    }
+
+    pub fn banking_snapshot(&self) -> Vec<u8> {
+        self.context.cartridge.banking_snapshot()
+    }
+
+    pub fn restore_banking(&mut self, snapshot: &[u8]) {
+        self.context.cartridge.restore_banking(snapshot)
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.context.raise_interrupt(interrupt)
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if let Some(log) = &mut self.input_log {
+            log.push(InputEvent {
+                cycle: self.context.clock_counter,
+                button,
+                pressed,
+            });
+        }
+        self.context
+            .joypad
+            .set_button(button, pressed, &mut self.context.interrupt_controller)
+    }
+
+    // Turning recording off throws away the log
+    pub fn enable_input_recording(&mut self, enabled: bool) {
+        match (enabled, &self.input_log) {
+            (true, None) => self.input_log = Some(Vec::new()),
+            (false, _) => self.input_log = None,
+            _ => {}
+        }
+    }
+
+    pub fn input_log(&self) -> Option<&[InputEvent]> {
+        self.input_log.as_deref()
+    }
+
+    // run_frames applies each input at the first operation boundary at or after its cycle, which
+    // is exactly where it was recorded when starting from the same state
+    pub fn replay_input(&mut self, log: Vec<InputEvent>) {
+        self.input_replay = log.into();
+    }
+
+    fn apply_replayed_input(&mut self) {
+        while let Some(input) = self.input_replay.front() {
+            if input.cycle > self.context.clock_counter {
+                break;
+            }
+            self.context.joypad.set_button(
+                input.button,
+                input.pressed,
+                &mut self.context.interrupt_controller,
+            );
+            self.input_replay.pop_front();
+        }
+    }
+
+    pub fn set_dpad_conflict_policy(&mut self, policy: DpadConflictPolicy) {
+        self.context.joypad.set_dpad_conflict_policy(policy)
+    }
+
+    pub fn set_frame_skip(&mut self, frame_skip: u32) {
+        self.context.ppu.set_frame_skip(frame_skip)
+    }
+
+    pub fn attach_frame_sink(&mut self, frame_sink: impl FrameSink + 'static) {
+        self.context.ppu.set_frame_sink(Some(Box::new(frame_sink)))
+    }
+
+    pub fn detach_frame_sink(&mut self) {
+        self.context.ppu.set_frame_sink(None)
+    }
+
+    pub fn attach_scanline_sink(&mut self, scanline_sink: impl ScanlineSink + 'static) {
+        self.context
+            .ppu
+            .set_scanline_sink(Some(Box::new(scanline_sink)))
+    }
+
+    pub fn detach_scanline_sink(&mut self) {
+        self.context.ppu.set_scanline_sink(None)
+    }
+
+    pub fn set_pixel_events(&mut self, enabled: bool) {
+        self.context.ppu.set_pixel_events(enabled)
+    }
+
+    // Without frame events, completed frames are only available through a sink or frame_buffer
+    pub fn set_frame_events(&mut self, enabled: bool) {
+        self.context.ppu.set_frame_events(enabled)
+    }
+
+    pub fn set_vram_events(&mut self, enabled: bool) {
+        self.context.ppu.set_vram_events(enabled)
+    }
+
+    pub fn set_double_buffering(&mut self, enabled: bool) {
+        self.context.ppu.set_double_buffering(enabled)
+    }
+
+    pub fn frame_buffer(&self) -> &Buffer {
+        self.context.ppu.frame_buffer()
+    }
+
+    // Whether a frame was completed since the last take_frame, independent of frame events
+    pub fn frame_ready(&self) -> bool {
+        self.context.ppu.frame_ready()
+    }
+
+    pub fn take_frame(&mut self) -> Option<Buffer> {
+        self.context.ppu.take_frame()
+    }
+
+    // Offsets from 0x8000 and 0xC000, for test setup
+    pub fn load_vram(&mut self, offset: u16, data: &[u8]) {
+        self.context.ppu.load_vram(offset, data)
+    }
+
+    pub fn load_wram(&mut self, offset: u16, data: &[u8]) {
+        self.context.wram.load_bytes(offset, data)
+    }
+
+    pub fn reset_component(&mut self, component: Component) {
+        match component {
+            Component::Ppu => self.context.ppu.reset(),
+            Component::Timer => self.context.timer.reset(),
+            Component::Serial => self.context.serial.reset(),
+            Component::InterruptController => self.context.interrupt_controller.reset(),
+        }
+    }
+
+    // Turning profiling off throws away the histogram
+    pub fn enable_profiling(&mut self, enabled: bool) {
+        match (enabled, &self.context.profile) {
+            (true, None) => self.context.profile = Some(Box::default()),
+            (false, _) => self.context.profile = None,
+            _ => {}
+        }
+    }
+
+    pub fn opcode_histogram(&self) -> Option<&OpcodeHistogram> {
+        self.context.profile.as_deref()
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.context.boot_rom.is_mapped()
+    }
+
+    // Channel 3 is the only channel with output so far
+    pub fn wave_output(&self) -> u8 {
+        self.context.apu.wave_output()
+    }
+
+    pub fn div_counter(&self) -> u16 {
+        self.context.timer.div_counter()
+    }
+
+    pub fn pending_interrupts(&self) -> Vec<Interrupt> {
+        self.context.interrupt_controller.pending().collect()
+    }
+
+    pub fn ppu_registers(&self) -> PpuRegisters {
+        self.context.ppu.registers()
+    }
+
+    pub fn bg_tile_map(&self, which: TileMap) -> [[u8; 32]; 32] {
+        self.context.ppu.tile_map(which)
+    }
+
+    // Always CYCLES_PER_FRAME once the LCD has been on for a whole frame, 0 before that
+    pub fn last_frame_cycles(&self) -> u64 {
+        self.context.ppu.last_frame_cycles()
+    }
+
+    pub fn set_sprite_limit(&mut self, sprite_limit: Option<u8>) {
+        self.context.ppu.set_sprite_limit(sprite_limit)
+    }
+
     pub fn dump(&mut self, base: &str) {
         let p = Path::new(base);
         if !p.exists() {
             fs::create_dir(p).unwrap();
         }
-        log::info!("Dumping...");
+        info!("Dumping...");
         let mut v = Vec::with_capacity(64 * KIB);
         for i in 0..64 * KIB {
             v.push(self.context.read(i as u16));
@@ -257,10 +1253,15 @@ impl GameBoy {
             format!("{}", self.context.interrupt_controller),
         )
         .unwrap();
-        log::info!("Dump done!")
+        info!("Dump done!")
     }
 }
 
+// VRAM, OAM, IO and the unusable region in between can't hold code
+fn is_executable(address: u16) -> bool {
+    !matches!(address, 0x8000..=0x9FFF | 0xFE00..=0xFF7F | 0xFFFF)
+}
+
 pub trait Addressable {
     #[must_use]
     fn read(&self, address: u16) -> Option<u8>;
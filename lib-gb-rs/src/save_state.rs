@@ -0,0 +1,136 @@
+use thiserror::Error;
+
+use crate::components::cartridge::LoadError;
+
+// Bump on any change to the layout of the state
+pub const STATE_VERSION: u16 = 6;
+const MAGIC: &[u8; 4] = b"GBRS";
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("State version {found} does not match {expected}")]
+    VersionMismatch { found: u16, expected: u16 },
+    #[error("State is truncated")]
+    Truncated,
+    #[error("State was saved with a different cartridge")]
+    CartridgeMismatch,
+    #[error("Invalid state: {0}")]
+    Invalid(&'static str),
+    #[error(transparent)]
+    Rom(#[from] LoadError),
+}
+
+pub trait SaveState {
+    fn save(&self, w: &mut StateWriter);
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(cartridge_checksum: [u8; 3]) -> Self {
+        let mut w = Self::default();
+        w.bytes(MAGIC);
+        w.u16(STATE_VERSION);
+        w.bytes(&cartridge_checksum);
+        w
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Length-prefixed, for data that isn't a fixed size
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.u16(bytes.len() as u16);
+        self.bytes(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8], cartridge_checksum: [u8; 3]) -> Result<Self, StateError> {
+        let mut r = Self { data };
+        if r.array::<4>()? != *MAGIC {
+            return Err(StateError::Invalid("not a save state"));
+        }
+        let found = r.u16()?;
+        if found != STATE_VERSION {
+            return Err(StateError::VersionMismatch {
+                found,
+                expected: STATE_VERSION,
+            });
+        }
+        if r.array::<3>()? != cartridge_checksum {
+            return Err(StateError::CartridgeMismatch);
+        }
+        Ok(r)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? > 0)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        self.fill(&mut array)?;
+        Ok(array)
+    }
+
+    pub fn fill(&mut self, target: &mut [u8]) -> Result<(), StateError> {
+        if self.data.len() < target.len() {
+            return Err(StateError::Truncated);
+        }
+        let (head, tail) = self.data.split_at(target.len());
+        target.copy_from_slice(head);
+        self.data = tail;
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.u16()? as usize;
+        let mut v = vec![0; len];
+        self.fill(&mut v)?;
+        Ok(v)
+    }
+}
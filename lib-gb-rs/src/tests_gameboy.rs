@@ -0,0 +1,1229 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::{
+    parse_into_cartridge, Addressable, Button, ClockContext, Color, Component, CpuState,
+    ExecutionEvent, GameBoy, Immediate16, Instruction, Interrupt, LoadError, MemAccess,
+    MemoryContext, Mode, Model, MooneyeResult, PendingOp, Register16, Register8, RunError,
+    StateError, CYCLES_PER_FRAME, KIB,
+};
+
+fn gameboy_with_program(program: &[u8]) -> GameBoy {
+    let mut rom = vec![0; 32 * KIB];
+    rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+    GameBoy::new(parse_into_cartridge(rom))
+}
+
+fn execute(gb: &mut GameBoy, operations: usize) {
+    for _ in 0..operations {
+        let (_, res) = gb.execute_operation();
+        res.unwrap();
+    }
+}
+
+fn run_cycles(gb: &mut GameBoy, cycles: u64) {
+    let target = gb.get_elapsed_cycles() + cycles;
+    while gb.get_elapsed_cycles() < target {
+        let (_, res) = gb.execute_operation();
+        res.unwrap();
+    }
+}
+
+#[test]
+fn cpu_state_halted() {
+    // DI; HALT
+    let mut gb = gameboy_with_program(&[0xF3, 0x76]);
+    assert_eq!(gb.cpu_state(), CpuState::Running);
+
+    execute(&mut gb, 2);
+    assert_eq!(gb.cpu_state(), CpuState::Halted);
+
+    execute(&mut gb, 10);
+    assert_eq!(gb.cpu_state(), CpuState::Halted);
+}
+
+// Select the d-pad, STOP, then INC B once woken
+const STOP_PROGRAM: [u8; 7] = [
+    0x3E, 0x20, // LD A, 0x20
+    0xE0, 0x00, // LDH (JOYP), A
+    0x10, 0x00, // STOP
+    0x04, // INC B
+];
+
+#[test]
+fn stop_wakes_on_joypad() {
+    let mut gb = gameboy_with_program(&STOP_PROGRAM);
+    let b = gb.cpu().read_register8(Register8::B);
+
+    execute(&mut gb, 3);
+    assert_eq!(gb.cpu_state(), CpuState::Stopped);
+    execute(&mut gb, 10);
+    assert_eq!(gb.cpu_state(), CpuState::Stopped);
+
+    // Buttons on the unselected line don't wake it
+    gb.set_button(Button::Start, true);
+    execute(&mut gb, 10);
+    assert_eq!(gb.cpu_state(), CpuState::Stopped);
+
+    gb.set_button(Button::Down, true);
+    execute(&mut gb, 1);
+    assert_eq!(gb.cpu_state(), CpuState::Running);
+
+    // Padding NOP, INC B
+    execute(&mut gb, 2);
+    assert_eq!(gb.cpu().read_register8(Register8::B), b.wrapping_add(1));
+}
+
+#[test]
+fn stop_with_button_held() {
+    let mut gb = gameboy_with_program(&STOP_PROGRAM);
+    gb.set_button(Button::Left, true);
+
+    execute(&mut gb, 3);
+    assert_eq!(gb.cpu_state(), CpuState::Running);
+}
+
+#[derive(Clone, Default)]
+struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn serial_printer() {
+    // Send 'H' and 'i' over serial with the internal clock
+    let mut gb = gameboy_with_program(&[
+        0x3E, b'H', // LD A, 'H'
+        0xE0, 0x01, // LDH (SB), A
+        0x3E, 0x81, // LD A, 0x81
+        0xE0, 0x02, // LDH (SC), A
+        0xF0, 0x02, // LDH A, (SC)
+        0xCB, 0x7F, // BIT 7, A
+        0x20, 0xFA, // JR NZ, -6
+        0x3E, b'i', // LD A, 'i'
+        0xE0, 0x01, // LDH (SB), A
+        0x3E, 0x81, // LD A, 0x81
+        0xE0, 0x02, // LDH (SC), A
+        0x18, 0xFE, // JR -2
+    ]);
+    let writer = SharedWriter::default();
+    gb.attach_serial_printer(writer.clone());
+
+    run_cycles(&mut gb, 20_000);
+
+    assert_eq!(writer.0.borrow().as_slice(), b"Hi");
+}
+
+#[test]
+fn serial_echo() {
+    let program = [
+        0x3E, b'H', // LD A, 'H'
+        0xE0, 0x01, // LDH (SB), A
+        0x3E, 0x81, // LD A, 0x81
+        0xE0, 0x02, // LDH (SC), A
+        0x18, 0xFE, // JR -2
+    ];
+    let mut gb = gameboy_with_program(&program);
+    let echo = SharedWriter::default();
+    gb.set_serial_echo_to(echo.clone());
+    run_cycles(&mut gb, 10_000);
+    assert_eq!(echo.0.borrow().as_slice(), b"H");
+
+    // Turning echo off leaves an attached printer alone
+    let mut gb = gameboy_with_program(&program);
+    let echo = SharedWriter::default();
+    let printer = SharedWriter::default();
+    gb.attach_serial_printer(printer.clone());
+    gb.set_serial_echo_to(echo.clone());
+    gb.set_serial_echo(false);
+    run_cycles(&mut gb, 10_000);
+    assert!(echo.0.borrow().is_empty());
+    assert_eq!(printer.0.borrow().as_slice(), b"H");
+}
+
+#[test]
+fn trace_memory() {
+    let mut gb = gameboy_with_program(&[
+        0x21, 0x00, 0xC0, // LD HL, 0xC000
+        0x3E, 0x42, // LD A, 0x42
+        0x22, // LD (HL+), A
+        0x22, // LD (HL+), A
+        0xEA, 0x10, 0xC0, // LD (0xC010), A
+        0xFA, 0x00, 0xC0, // LD A, (0xC000)
+        0x18, 0xFE, // JR -2
+    ]);
+    let accesses = Rc::new(RefCell::new(Vec::new()));
+    let traced = accesses.clone();
+    gb.trace_memory(0xC000..=0xC00F, move |access| {
+        traced.borrow_mut().push(access)
+    });
+    execute(&mut gb, 10);
+
+    let access = |addr, is_write, pc| MemAccess {
+        addr,
+        value: 0x42,
+        is_write,
+        pc,
+    };
+    assert_eq!(
+        *accesses.borrow(),
+        [
+            access(0xC000, true, 0x0105),
+            access(0xC001, true, 0x0106),
+            access(0xC000, false, 0x010A),
+        ]
+    );
+
+    gb.stop_tracing_memory();
+    gb.write_register16(Register16::PC, 0x010A);
+    execute(&mut gb, 1);
+    assert_eq!(accesses.borrow().len(), 3);
+}
+
+#[test]
+fn execution_guard() {
+    let mut gb = gameboy_with_program(&[0x00]);
+    gb.set_execution_guard(true);
+
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::ExecutingInvalidRegion(_))));
+
+    gb.write_register16(Register16::PC, 0x8000);
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::ExecutingInvalidRegion(a) if a.0 == 0x8000)));
+}
+
+#[test]
+fn detect_self_loop() {
+    let mut gb = gameboy_with_program(&[
+        0x00, // NOP
+        0x18, 0xFE, // JR -2
+    ]);
+    let self_loop = |events: &[ExecutionEvent]| {
+        events.iter().find_map(|e| match e {
+            ExecutionEvent::SelfLoopDetected(a) => Some(a.0),
+            _ => None,
+        })
+    };
+
+    // Off by default
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert_eq!(self_loop(&events), None);
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert_eq!(self_loop(&events), None);
+
+    gb.set_detect_self_loop(true);
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert_eq!(self_loop(&events), Some(0x0101));
+
+    gb.write_register16(Register16::PC, 0x0100);
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert_eq!(self_loop(&events), None);
+}
+
+#[test]
+fn request_interrupt() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x01, // LD A, 0x01
+        0xE0, 0xFF, // LDH (IE), A
+        0xFB, // EI
+        0x00, // NOP
+        0x18, 0xFE, // JR -2
+    ]);
+    execute(&mut gb, 4);
+
+    gb.request_interrupt(Interrupt::VBlank);
+    let mut events = Vec::new();
+    for _ in 0..2 {
+        let (e, res) = gb.execute_operation();
+        res.unwrap();
+        events.extend(e);
+    }
+
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::InterruptRaised(Interrupt::VBlank))));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ExecutionEvent::InterruptRoutineFinished(Interrupt::VBlank)
+    )));
+    let pc = gb.cpu().read_register16(Register16::PC);
+    assert!((0x0040..0x0048).contains(&pc), "PC {:#06x}", pc);
+}
+
+#[test]
+fn reti_into_halt_with_interrupt_pending() {
+    let mut rom = rom_with_program(
+        &[
+            0x3E, 0x05, // LD A, 0x05
+            0xE0, 0xFF, // LDH (IE), A
+            0xFB, // EI
+            0x00, // NOP
+            0x76, // HALT
+            0x18, 0xFE, // JR -2
+        ],
+        0x00,
+    );
+    rom[0x0040] = 0xD9; // RETI
+    rom[0x0050] = 0xD9; // RETI
+    let mut gb = GameBoy::new(parse_into_cartridge(rom));
+    gb.request_interrupt(Interrupt::VBlank);
+    gb.request_interrupt(Interrupt::Timer);
+
+    let mut events = Vec::new();
+    for _ in 0..12 {
+        let (e, res) = gb.execute_operation();
+        res.unwrap();
+        events.extend(e.into_iter().filter(|e| {
+            matches!(
+                e,
+                ExecutionEvent::InterruptRoutineFinished(_) | ExecutionEvent::Halted
+            )
+        }));
+    }
+
+    // RETI enables interrupts right away, so the timer is dispatched before HALT is reached
+    assert!(matches!(
+        events.as_slice(),
+        [
+            ExecutionEvent::InterruptRoutineFinished(Interrupt::VBlank),
+            ExecutionEvent::InterruptRoutineFinished(Interrupt::Timer),
+            ExecutionEvent::Halted,
+            ..
+        ]
+    ));
+    assert_eq!(gb.cpu_state(), CpuState::Halted);
+}
+
+#[test]
+fn step_instruction() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x01, // LD A, 0x01
+        0xE0, 0xFF, // LDH (IE), A
+        0xFB, // EI
+        0x76, // HALT
+    ]);
+    let mut instructions = 0;
+    for _ in 0..4 {
+        let (events, res) = gb.step_instruction();
+        res.unwrap();
+        instructions += events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::InstructionExecuted { .. }))
+            .count();
+    }
+    assert_eq!(instructions, 4);
+    assert_eq!(gb.cpu_state(), CpuState::Halted);
+
+    // Halted operations and the dispatch are part of the step to the handler's first instruction
+    gb.request_interrupt(Interrupt::VBlank);
+    let (events, res) = gb.step_instruction();
+    assert_eq!(res.unwrap(), Instruction::Nop);
+    assert_eq!(
+        events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::InstructionExecuted { .. }))
+            .count(),
+        1
+    );
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ExecutionEvent::InterruptRoutineFinished(Interrupt::VBlank)
+    )));
+    assert_eq!(gb.cpu().read_register16(Register16::PC), 0x0042);
+}
+
+#[test]
+fn run_instructions() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x01, // LD A, 0x01
+        0xE0, 0xFF, // LDH (IE), A
+        0xFB, // EI
+        0x00, // NOP
+        0x00, // NOP
+    ]);
+    gb.request_interrupt(Interrupt::VBlank);
+    let (events, res) = gb.run_instructions(5);
+    res.unwrap();
+    assert_eq!(
+        events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::InstructionExecuted { .. }))
+            .count(),
+        5
+    );
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::InterruptRoutineStarted)));
+}
+
+#[test]
+fn take_frame() {
+    let mut gb = gameboy_with_program(&[0x18, 0xFE]); // JR -2
+    gb.set_frame_events(false);
+    gb.set_pixel_events(false);
+    assert!(!gb.frame_ready());
+    assert!(gb.take_frame().is_none());
+
+    let start = gb.get_elapsed_cycles();
+    while !gb.frame_ready() {
+        execute(&mut gb, 1);
+        assert!(gb.get_elapsed_cycles() - start <= CYCLES_PER_FRAME);
+    }
+    assert!(gb.take_frame().is_some());
+    assert!(!gb.frame_ready());
+    assert!(gb.take_frame().is_none());
+}
+
+#[test]
+fn div_counter() {
+    let mut gb = gameboy_with_program(&[0x00, 0x18, 0xFD]);
+    let start = gb.div_counter();
+    let start_cycles = gb.get_elapsed_cycles();
+    run_cycles(&mut gb, 70_000);
+
+    let elapsed = gb.get_elapsed_cycles() - start_cycles;
+    assert_eq!(gb.div_counter(), start.wrapping_add(elapsed as u16));
+}
+
+#[test]
+fn load_vram_in_mode_3() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0xFC, // LD A, 0xFC
+        0xE0, 0x47, // LDH (BGP), A
+        0x3E, 0x91, // LD A, 0x91
+        0xE0, 0x40, // LDH (LCDC), A
+        0x18, 0xFE, // JR -2
+    ]);
+    // Keep drawing into the same buffer
+    gb.set_frame_events(false);
+    while gb.ppu_registers().mode != Mode::LCDOn3 {
+        execute(&mut gb, 1);
+    }
+
+    // Tile 1 is solid color 3, at the top left of the tile map
+    gb.load_vram(0x0010, &[0xFF; 16]);
+    gb.load_vram(0x1800, &[0x01]);
+    gb.load_wram(0x0100, &[0x12, 0x34]);
+    run_cycles(&mut gb, 2 * CYCLES_PER_FRAME);
+
+    let frame = gb.frame_buffer();
+    for y in 0..8 {
+        for x in 0..8 {
+            assert_eq!(frame[y][x], Color::Black, "({}, {})", x, y);
+        }
+        assert_eq!(frame[y][8], Color::White);
+    }
+    assert_eq!(frame[8][0], Color::White);
+    assert_eq!(gb.context.wram.read(0xC100), Some(0x12));
+    assert_eq!(gb.context.wram.read(0xC101), Some(0x34));
+}
+
+#[test]
+fn native_rates() {
+    let frame_rate = GameBoy::CPU_HZ as f64 / (154 * 456) as f64;
+    assert!((frame_rate - GameBoy::FRAME_RATE).abs() < 1e-3);
+    assert_eq!(GameBoy::CPU_HZ / CYCLES_PER_FRAME, 59);
+    assert_eq!(GameBoy::AUDIO_NATIVE_HZ, 2_097_152);
+}
+
+#[test]
+fn run_until() {
+    let mut gb = gameboy_with_program(&[0x18, 0xFE]); // JR -2
+    gb.set_pixel_events(false);
+    let event = gb
+        .run_until(
+            |e| matches!(e, ExecutionEvent::FrameReady(_)),
+            CYCLES_PER_FRAME,
+        )
+        .unwrap();
+    assert!(matches!(event, ExecutionEvent::FrameReady(_)));
+    assert!(gb.get_elapsed_cycles() <= CYCLES_PER_FRAME);
+
+    let res = gb.run_until(|e| matches!(e, ExecutionEvent::SerialOut(_)), 1000);
+    assert!(matches!(res, Err(RunError::Timeout(1000))));
+}
+
+#[test]
+fn run_frames_with_turbo() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x91, // LD A, 0x91
+        0xE0, 0x40, // LDH (LCDC), A
+        0x18, 0xFE, // JR -2
+    ]);
+    gb.set_pixel_events(false);
+    gb.set_turbo(4);
+    let (events, res) = gb.run_frames(1);
+    res.unwrap();
+
+    let cycles = gb.get_elapsed_cycles();
+    assert!(
+        (4 * CYCLES_PER_FRAME..4 * CYCLES_PER_FRAME + 24).contains(&cycles),
+        "{} cycles",
+        cycles
+    );
+    let frames = events
+        .iter()
+        .filter(|e| matches!(e, ExecutionEvent::FrameReady(_)))
+        .count();
+    assert_eq!(frames, 4);
+
+    gb.set_turbo(0);
+    assert_eq!(gb.turbo(), 1);
+}
+
+fn ly_wait_gameboy() -> GameBoy {
+    let mut rom = vec![0; 32 * KIB];
+    rom[0x0040] = 0xD9; // RETI
+    let program = [
+        0x3E, 0x01, // LD A, 0x01
+        0xE0, 0xFF, // LDH (IE), A
+        0xFB, // EI
+        0xF0, 0x44, // LDH A, (LY)
+        0xFE, 0x90, // CP 0x90
+        0x20, 0xFA, // JR NZ, -6
+        0x04, // INC B
+        0xF0, 0x44, // LDH A, (LY)
+        0xFE, 0x90, // CP 0x90
+        0x28, 0xFA, // JR Z, -6
+        0x0C, // INC C
+        0x18, 0xF0, // JR -16
+    ];
+    rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+    GameBoy::new(parse_into_cartridge(rom))
+}
+
+#[test]
+fn idle_skip_matches_full_run() {
+    let count_instructions = |events: &[ExecutionEvent]| {
+        events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::InstructionExecuted { .. }))
+            .count()
+    };
+
+    let mut full = ly_wait_gameboy();
+    full.set_pixel_events(false);
+    let (full_events, res) = full.run_frames(3);
+    res.unwrap();
+
+    let mut skipped = ly_wait_gameboy();
+    skipped.set_pixel_events(false);
+    skipped.set_idle_skip(true);
+    let (skipped_events, res) = skipped.run_frames(3);
+    res.unwrap();
+
+    assert!(full.cpu().read_register8(Register8::B) >= 2);
+    assert_eq!(skipped.get_elapsed_cycles(), full.get_elapsed_cycles());
+    assert_eq!(skipped.pending_operation(), full.pending_operation());
+    assert_eq!(skipped.save_state(), full.save_state());
+    assert!(count_instructions(&skipped_events) < count_instructions(&full_events) / 10);
+}
+
+// Copies the d-pad lines into BGP, so the blank background shows the pressed directions
+fn joypad_palette_gameboy() -> GameBoy {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x20, // LD A, 0x20
+        0xE0, 0x00, // LDH (JOYP), A
+        0xF0, 0x00, // LDH A, (JOYP)
+        0xE0, 0x47, // LDH (BGP), A
+        0x18, 0xFA, // JR -6
+    ]);
+    gb.set_pixel_events(false);
+    gb
+}
+
+fn frame_hash(gb: &GameBoy) -> u64 {
+    let colors: Vec<_> = gb.frame_buffer().flatten().map(|c| c as u8).collect();
+    let mut hasher = DefaultHasher::new();
+    colors.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn run_frames_with_input(gb: &mut GameBoy) {
+    gb.run_frames(2).1.unwrap();
+    gb.set_button(Button::Right, true);
+    gb.run_frames(1).1.unwrap();
+    gb.set_button(Button::Right, false);
+    gb.set_button(Button::Left, true);
+    gb.run_frames(2).1.unwrap();
+}
+
+#[test]
+fn record_and_replay_input() {
+    let mut gb = joypad_palette_gameboy();
+    let start = gb.save_state();
+    gb.enable_input_recording(true);
+    run_frames_with_input(&mut gb);
+    let log = gb.input_log().unwrap().to_vec();
+    assert_eq!(
+        log.iter()
+            .map(|i| (i.frame(), i.button, i.pressed))
+            .collect::<Vec<_>>(),
+        [
+            (2, Button::Right, true),
+            (3, Button::Right, false),
+            (3, Button::Left, true)
+        ]
+    );
+
+    let mut replay = joypad_palette_gameboy();
+    replay.load_state(&start).unwrap();
+    replay.replay_input(log);
+    replay.run_frames(2).1.unwrap();
+    replay.run_frames(1).1.unwrap();
+    replay.run_frames(2).1.unwrap();
+    assert_eq!(replay.get_elapsed_cycles(), gb.get_elapsed_cycles());
+    assert_eq!(frame_hash(&replay), frame_hash(&gb));
+    assert_eq!(replay.save_state(), gb.save_state());
+
+    let mut without_input = joypad_palette_gameboy();
+    without_input.run_frames(5).1.unwrap();
+    assert_ne!(frame_hash(&without_input), frame_hash(&gb));
+
+    gb.enable_input_recording(false);
+    assert!(gb.input_log().is_none());
+}
+
+#[test]
+fn opcode_histogram() {
+    let mut gb = gameboy_with_program(&[
+        0x04, // INC B
+        0x0D, // DEC C
+        0xCB, 0x37, // SWAP A
+        0x18, 0xFA, // JR -6
+    ]);
+    assert!(gb.opcode_histogram().is_none());
+    gb.enable_profiling(true);
+    for _ in 0..4 * 10 {
+        gb.step_instruction().1.unwrap();
+    }
+
+    let histogram = gb.opcode_histogram().unwrap();
+    for opcode in [0x04, 0x0D, 0xCB, 0x18] {
+        assert_eq!(histogram.opcodes[opcode], 10, "opcode {:#04x}", opcode);
+    }
+    assert_eq!(histogram.opcodes.iter().sum::<u64>(), 40);
+    assert_eq!(histogram.cb_opcodes[0x37], 10);
+    assert_eq!(histogram.cb_opcodes.iter().sum::<u64>(), 10);
+
+    gb.enable_profiling(false);
+    assert!(gb.opcode_histogram().is_none());
+}
+
+#[test]
+fn high_ram_and_ie_boundary() {
+    let mut gb = gameboy_with_program(&[]);
+    gb.context.write(0xFF80, 0x3C);
+    gb.context.write(0xFFFE, 0xA5);
+    gb.context.write(0xFFFF, 0x1A);
+    assert_eq!(gb.context.read(0xFF80), 0x3C);
+    assert_eq!(gb.context.read(0xFFFE), 0xA5);
+    assert_eq!(gb.context.read(0xFFFF), 0x1A);
+
+    gb.context.write(0xFFFF, 0x05);
+    assert_eq!(gb.context.read(0xFFFE), 0xA5);
+    gb.context.write(0xFFFE, 0x5A);
+    assert_eq!(gb.context.read(0xFFFF), 0x05);
+
+    // Nothing else claims either byte, and 0xFF7F is unmapped
+    assert_eq!(gb.context.high_ram.read(0xFFFF), None);
+    assert_eq!(gb.context.high_ram.read(0xFF7F), None);
+    assert_eq!(gb.context.interrupt_controller.read(0xFFFE), None);
+    assert_eq!(gb.context.read(0xFF7F), 0xFF);
+}
+
+#[test]
+fn reset_timer() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x05, // LD A, 0x05
+        0xE0, 0x07, // LDH (TAC), A
+        0x18, 0xFE, // JR -2
+    ]);
+    run_cycles(&mut gb, 10_000);
+    assert_ne!(gb.div_counter(), 0);
+    assert_ne!(gb.context.timer.read(0xFF05), Some(0));
+    let cpu = gb.cpu().clone();
+
+    gb.reset_component(Component::Timer);
+    assert_eq!(gb.div_counter(), 0);
+    assert_eq!(gb.context.timer.read(0xFF05), Some(0));
+    assert_eq!(gb.context.timer.read(0xFF07), Some(0));
+    assert_eq!(gb.cpu(), &cpu);
+}
+
+fn boot_registers(model: Model) -> [u16; 5] {
+    let rom = vec![0; 32 * KIB];
+    let gb = GameBoy::new_with_model(parse_into_cartridge(rom), model);
+    [
+        Register16::AF,
+        Register16::BC,
+        Register16::DE,
+        Register16::HL,
+        Register16::SP,
+    ]
+    .map(|r| gb.cpu().read_register16(r))
+}
+
+#[test]
+fn boot_registers_per_model() {
+    assert_eq!(
+        boot_registers(Model::Dmg),
+        [0x01B0, 0x0013, 0x00D8, 0x014D, 0xFFFE]
+    );
+    assert_eq!(
+        boot_registers(Model::Mgb),
+        [0xFFB0, 0x0013, 0x00D8, 0x014D, 0xFFFE]
+    );
+    assert_eq!(
+        boot_registers(Model::Sgb),
+        [0x0100, 0x0014, 0x0000, 0xC060, 0xFFFE]
+    );
+    assert_eq!(
+        boot_registers(Model::Cgb),
+        [0x1180, 0x0000, 0xFF56, 0x000D, 0xFFFE]
+    );
+}
+
+#[test]
+fn pending_operation() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x01, // LD A, 0x01
+        0xE0, 0xFF, // LDH (IE), A
+        0xFB, // EI
+        0x04, // INC B
+    ]);
+    execute(&mut gb, 3);
+    assert_eq!(gb.pending_operation(), PendingOp::Opcode(0x04));
+
+    gb.request_interrupt(Interrupt::VBlank);
+    execute(&mut gb, 1);
+    assert_eq!(gb.pending_operation(), PendingOp::StartInterruptRoutine);
+    execute(&mut gb, 1);
+    assert_eq!(gb.pending_operation(), PendingOp::Opcode(0x00));
+}
+
+fn ppu_timing_events(pixels: bool) -> (Vec<String>, u64) {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x91, // LD A, 0x91
+        0xE0, 0x40, // LDH (LCDC), A
+        0x18, 0xFE, // JR -2
+    ]);
+    gb.set_pixel_events(pixels);
+    gb.set_frame_events(pixels);
+
+    let mut timing = Vec::new();
+    for _ in 0..20_000 {
+        let (events, res) = gb.execute_operation();
+        res.unwrap();
+        for e in events {
+            match e {
+                ExecutionEvent::InterruptRaised(_) | ExecutionEvent::PpuModeSwitch { .. } => {
+                    timing.push(format!("{} {}", gb.get_elapsed_cycles(), e))
+                }
+                ExecutionEvent::PpuPixelPushed(..) | ExecutionEvent::FrameReady(_) => {
+                    assert!(pixels, "Pixels rendered without consumers")
+                }
+                _ => {}
+            }
+        }
+    }
+    (timing, gb.get_elapsed_cycles())
+}
+
+#[test]
+fn pixel_fast_path_timing() {
+    let (with_pixels, cycles_with_pixels) = ppu_timing_events(true);
+    let (without_pixels, cycles_without_pixels) = ppu_timing_events(false);
+
+    assert!(with_pixels.iter().any(|e| e.contains("mode: VBlank1")));
+    assert_eq!(with_pixels, without_pixels);
+    assert_eq!(cycles_with_pixels, cycles_without_pixels);
+}
+
+#[test]
+fn from_rom_path() {
+    let gb = GameBoy::from_rom_path("vendored_test_roms/blargg/instr_timing/instr_timing.gb");
+    assert!(gb.is_ok());
+
+    let gb = GameBoy::from_rom_path("vendored_test_roms/does_not_exist.gb");
+    assert!(matches!(gb, Err(LoadError::Io(_))));
+}
+
+#[test]
+fn ppu_registers() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x12, // LD A, 0x12
+        0xE0, 0x42, // LDH (SCY), A
+        0x3E, 0x34, // LD A, 0x34
+        0xE0, 0x43, // LDH (SCX), A
+    ]);
+    execute(&mut gb, 4);
+
+    let registers = gb.ppu_registers();
+    assert_eq!((registers.scy, registers.scx), (0x12, 0x34));
+}
+
+fn rom_with_program(program: &[u8], header_checksum: u8) -> Vec<u8> {
+    let mut rom = vec![0; 32 * KIB];
+    rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+    rom[0x014D] = header_checksum;
+    rom
+}
+
+// Count up B in WRAM forever
+const COUNTER_PROGRAM: [u8; 8] = [
+    0x04, // INC B
+    0x78, // LD A, B
+    0xEA, 0x00, 0xC0, // LD (0xC000), A
+    0xC3, 0x00, 0x01, // JP 0x0100
+];
+
+#[test]
+fn save_and_load_state() {
+    let mut gb = gameboy_with_program(&COUNTER_PROGRAM);
+    execute(&mut gb, 10);
+    let state = gb.save_state();
+    let cpu = gb.cpu().clone();
+    let cycles = gb.get_elapsed_cycles();
+
+    execute(&mut gb, 10);
+    assert_ne!(gb.cpu(), &cpu);
+
+    gb.load_state(&state).unwrap();
+    assert_eq!(gb.cpu(), &cpu);
+    assert_eq!(gb.get_elapsed_cycles(), cycles);
+    assert_eq!(gb.save_state(), state);
+}
+
+#[test]
+fn from_state_and_rom() {
+    let rom = rom_with_program(&COUNTER_PROGRAM, 0x12);
+    let mut gb = GameBoy::new(parse_into_cartridge(rom.clone()));
+    run_cycles(&mut gb, 10_000);
+    let state = gb.save_state();
+
+    let restored = GameBoy::from_state_and_rom(&state, rom).unwrap();
+    assert_eq!(restored.cpu(), gb.cpu());
+    assert_eq!(restored.ppu_registers(), gb.ppu_registers());
+    assert_eq!(restored.get_elapsed_cycles(), gb.get_elapsed_cycles());
+    assert_eq!(restored.save_state(), state);
+
+    let other_rom = rom_with_program(&COUNTER_PROGRAM, 0x34);
+    assert!(matches!(
+        GameBoy::from_state_and_rom(&state, other_rom),
+        Err(StateError::CartridgeMismatch)
+    ));
+    assert!(matches!(
+        GameBoy::from_state_and_rom(&state, vec![0; 0x100]),
+        Err(StateError::Rom(LoadError::TooSmall(0x100)))
+    ));
+}
+
+#[test]
+fn state_checksum_ignores_banking() {
+    // 1 MiB of MBC1, so mode 1 maps bank 32 over the header
+    let mut rom = vec![0; 1024 * KIB];
+    rom[0x0147] = 0x01;
+    rom[0x014D] = 0x5A;
+    let mut gb = GameBoy::new(parse_into_cartridge(rom.clone()));
+    gb.context.write(0x6000, 0x01);
+    gb.context.write(0x4000, 0x01);
+    assert_eq!(gb.context.read(0x014D), 0x00);
+
+    let state = gb.save_state();
+    let restored = GameBoy::from_state_and_rom(&state, rom).unwrap();
+    assert_eq!(restored.save_state(), state);
+}
+
+#[test]
+fn swap_cartridge() {
+    let mut gb = gameboy_with_program(&COUNTER_PROGRAM);
+    execute(&mut gb, 20);
+    let cpu = gb.cpu().clone();
+    let counter = gb.context.wram.read(0xC000);
+    assert_eq!(gb.context.read_mapped(0x0200), Some(0x00));
+
+    let mut rom = rom_with_program(&COUNTER_PROGRAM, 0x00);
+    rom[0x0200] = 0x12;
+    gb.swap_cartridge(parse_into_cartridge(rom));
+    assert_eq!(gb.cpu(), &cpu);
+    assert_eq!(gb.context.wram.read(0xC000), counter);
+    assert_eq!(gb.context.read_mapped(0x0200), Some(0x12));
+
+    // Still running the same program
+    execute(&mut gb, 20);
+    assert_ne!(gb.context.wram.read(0xC000), counter);
+}
+
+#[test]
+fn clock_divider() {
+    let ly_after_4_lines = |divider| {
+        let mut gb = gameboy_with_program(&[0x18, 0xFE]); // JR -2
+        gb.set_clock_divider(divider);
+        assert_eq!(gb.ppu_registers().ly, 144);
+        run_cycles(&mut gb, 4 * 456);
+        gb.ppu_registers().ly
+    };
+    assert_eq!(ly_after_4_lines(1), 148);
+    assert_eq!(ly_after_4_lines(2), 146);
+    assert_eq!(ly_after_4_lines(0), 148);
+}
+
+#[test]
+fn load_state_errors() {
+    let mut gb = GameBoy::new(parse_into_cartridge(rom_with_program(&[0x00], 0x12)));
+    let state = gb.save_state();
+
+    let mut other = GameBoy::new(parse_into_cartridge(rom_with_program(&[0x00], 0x34)));
+    assert!(matches!(
+        other.load_state(&state),
+        Err(StateError::CartridgeMismatch)
+    ));
+
+    assert!(matches!(
+        gb.load_state(&state[..state.len() - 1]),
+        Err(StateError::Truncated)
+    ));
+
+    let mut old_version = state.clone();
+    old_version[4] = old_version[4].wrapping_sub(1);
+    assert!(matches!(
+        gb.load_state(&old_version),
+        Err(StateError::VersionMismatch { .. })
+    ));
+}
+
+#[test]
+fn failed_load_leaves_state_alone() {
+    let mut gb = gameboy_with_program(&COUNTER_PROGRAM);
+    let earlier = gb.save_state();
+    run_cycles(&mut gb, 10_000);
+    let current = gb.save_state();
+
+    // After the 9 byte header and 13 bytes of CPU, past which a half load would show
+    let mut invalid = earlier.clone();
+    invalid[22] = 2;
+    assert!(matches!(
+        gb.load_state(&invalid),
+        Err(StateError::Invalid("next operation"))
+    ));
+    assert_eq!(gb.save_state(), current);
+
+    assert!(matches!(
+        gb.load_state(&earlier[..earlier.len() - 1]),
+        Err(StateError::Truncated)
+    ));
+    assert_eq!(gb.save_state(), current);
+}
+
+#[test]
+fn frame_ready_with_lcd_on() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x91, // LD A, 0x91
+        0xE0, 0x40, // LDH (LCDC), A
+        0x18, 0xFE, // JR -2
+    ]);
+
+    let mut frames = 0;
+    while gb.get_elapsed_cycles() < 2 * CYCLES_PER_FRAME {
+        let (events, res) = gb.execute_operation();
+        res.unwrap();
+        frames += events
+            .iter()
+            .filter(|e| matches!(e, ExecutionEvent::FrameReady(_)))
+            .count();
+    }
+    assert!(frames >= 1);
+}
+
+#[test]
+fn last_frame_cycles() {
+    let mut gb = gameboy_with_program(&[0x18, 0xFE]); // JR -2
+    gb.set_pixel_events(false);
+    let frame_ready = |e: &ExecutionEvent| matches!(e, ExecutionEvent::FrameReady(_));
+
+    // Booted in the middle of VBlank
+    gb.run_until(frame_ready, 2 * CYCLES_PER_FRAME).unwrap();
+    assert_eq!(gb.last_frame_cycles(), 0);
+    for _ in 0..2 {
+        gb.run_until(frame_ready, 2 * CYCLES_PER_FRAME).unwrap();
+        assert_eq!(gb.last_frame_cycles(), CYCLES_PER_FRAME);
+    }
+
+    // Turning the LCD off and on starts over. Turning it off delivers a white frame first.
+    gb.load_wram(
+        0,
+        &[
+            0xAF, // XOR A
+            0xE0, 0x40, // LDH (LCDC), A
+            0x3E, 0x91, // LD A, 0x91
+            0xE0, 0x40, // LDH (LCDC), A
+            0x18, 0xFE, // JR -2
+        ],
+    );
+    gb.write_register16(Register16::PC, 0xC000);
+    gb.run_until(frame_ready, CYCLES_PER_FRAME).unwrap();
+    gb.run_until(frame_ready, 2 * CYCLES_PER_FRAME).unwrap();
+    assert_eq!(gb.last_frame_cycles(), 0);
+    gb.run_until(frame_ready, 2 * CYCLES_PER_FRAME).unwrap();
+    assert_eq!(gb.last_frame_cycles(), CYCLES_PER_FRAME);
+}
+
+#[test]
+fn diagnostic_mode() {
+    let program = vec![
+        0x3E, 0x05, // LD A, 5
+        0x47, // LD B, A
+        0x76, // HALT
+    ];
+    let mut gb = GameBoy::diagnostic(program).unwrap();
+    assert_eq!(gb.cpu().read_register16(Register16::SP), 0xFFFE);
+
+    while gb.cpu_state() != CpuState::Halted {
+        let (_, res) = gb.execute_operation();
+        res.unwrap();
+    }
+    assert_eq!(gb.cpu().read_register8(Register8::A), 5);
+    assert_eq!(gb.cpu().read_register8(Register8::B), 5);
+
+    let res = GameBoy::diagnostic(vec![0; 32 * KIB]);
+    assert!(matches!(res, Err(LoadError::ProgramTooLarge(..))));
+}
+
+#[test]
+fn boot_rom_disable_is_write_once() {
+    let mut rom = vec![0; 32 * KIB];
+    rom[0x0000] = 0xAB;
+    let boot_rom = vec![0x31; 0x100];
+    let mut gb = GameBoy::with_boot_rom(parse_into_cartridge(rom), boot_rom, Model::Dmg).unwrap();
+    assert_eq!(gb.cpu().read_register16(Register16::PC), 0x0001);
+    assert_eq!(gb.context.read(0x0000), 0x31);
+
+    gb.context.write(0xFF50, 0x01);
+    gb.context.write(0xFF50, 0x00);
+    assert!(!gb.boot_rom_mapped());
+    assert_eq!(gb.context.read(0xFF50), 0xFF);
+    assert_eq!(gb.context.read(0x0000), 0xAB);
+}
+
+#[test]
+fn cgb_boot_rom_leaves_header_visible() {
+    let mut rom = vec![0; 32 * KIB];
+    rom[0x0134] = b'T';
+    let boot_rom = vec![0x31; 0x900];
+    let mut gb = GameBoy::with_boot_rom(parse_into_cartridge(rom), boot_rom, Model::Cgb).unwrap();
+    assert_eq!(gb.context.read(0x0000), 0x31);
+    assert_eq!(gb.context.read(0x0134), b'T');
+    assert_eq!(gb.context.read(0x0200), 0x31);
+
+    let rom = vec![0; 32 * KIB];
+    let res = GameBoy::with_boot_rom(parse_into_cartridge(rom), vec![0; 0x200], Model::Cgb);
+    assert!(matches!(res, Err(LoadError::InvalidBootRomSize(0x200))));
+}
+
+#[test]
+fn disassemble_around() {
+    let mut gb = gameboy_with_program(&[
+        0x00, // NOP
+        0x3E, 0x05, // LD A, 5
+        0xC3, 0x50, 0x01, // JP 0x0150
+        0x47, // LD B, A
+        0x76, // HALT
+    ]);
+    execute(&mut gb, 2);
+
+    let listing = gb.disassemble_around(2, 2);
+    let addresses: Vec<_> = listing.iter().map(|(a, _, _)| *a).collect();
+    assert_eq!(addresses, [0x0100, 0x0101, 0x0103, 0x0106, 0x0107]);
+    let current: Vec<_> = listing.iter().filter(|(_, _, c)| *c).collect();
+    assert_eq!(
+        current,
+        [&(
+            0x0103,
+            Instruction::JumpImmediate(Immediate16(0x0150)),
+            true
+        )]
+    );
+}
+
+#[test]
+fn joypad_wakes_halt() {
+    let mut gb = gameboy_with_program(&[
+        0x3E, 0x10, // LD A, 0x10
+        0xE0, 0xFF, // LDH (IE), A
+        0xE0, 0x00, // LDH (P1), A
+        0xFB, // EI
+        0x76, // HALT
+    ]);
+    execute(&mut gb, 5);
+    run_cycles(&mut gb, 1000);
+    assert_eq!(gb.cpu_state(), CpuState::Halted);
+
+    gb.set_button(Button::Start, true);
+    let (events, res) = gb.step_instruction();
+    assert_eq!(res.unwrap(), Instruction::Nop);
+    assert!(events.iter().any(|e| matches!(
+        e,
+        ExecutionEvent::InterruptRoutineFinished(Interrupt::Joypad)
+    )));
+    assert_eq!(gb.cpu_state(), CpuState::Running);
+    assert_eq!(gb.cpu().read_register16(Register16::PC), 0x0062);
+}
+
+#[test]
+fn debug_trigger_disabled() {
+    let program = [
+        0x06, 0x12, // LD B, 0x12
+        0x40, // LD B, B
+        0x00, // NOP
+    ];
+    let mut gb = gameboy_with_program(&program);
+    execute(&mut gb, 1);
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::DebugTrigger)));
+
+    let mut gb = gameboy_with_program(&program);
+    gb.set_debug_trigger(false);
+    execute(&mut gb, 1);
+    let (events, res) = gb.execute_operation();
+    res.unwrap();
+    assert!(!events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::DebugTrigger)));
+    assert_eq!(gb.cpu().read_register8(Register8::B), 0x12);
+}
+
+fn mooneye_result_with(values: [u8; 6]) -> MooneyeResult {
+    let mut gb = gameboy_with_program(&[]);
+    let registers = [
+        Register8::B,
+        Register8::C,
+        Register8::D,
+        Register8::E,
+        Register8::H,
+        Register8::L,
+    ];
+    for (register, value) in registers.into_iter().zip(values) {
+        gb.write_register8(register, value);
+    }
+    gb.mooneye_result()
+}
+
+#[test]
+fn mooneye_result() {
+    assert_eq!(
+        mooneye_result_with([3, 5, 8, 13, 21, 34]),
+        MooneyeResult::Pass
+    );
+    assert_eq!(mooneye_result_with([0x42; 6]), MooneyeResult::Fail);
+    assert_eq!(
+        mooneye_result_with([3, 5, 8, 13, 21, 35]),
+        MooneyeResult::Fail
+    );
+}
+
+fn tick_until_mode(gb: &mut GameBoy, mode: Mode) {
+    while gb.ppu_registers().mode != mode {
+        gb.context.tick();
+    }
+}
+
+#[test]
+fn dma_from_vram_during_mode_3() {
+    let mut gb = gameboy_with_program(&[0x18, 0xFE]);
+    let pattern: Vec<u8> = (0..0xA0).collect();
+    gb.load_vram(0, &pattern);
+    tick_until_mode(&mut gb, Mode::OAMSearch2);
+    tick_until_mode(&mut gb, Mode::LCDOn3);
+
+    gb.context.write(0xFF46, 0x80);
+    // Byte i is copied 12 + 4 * i T-cycles after the write, after the PPU's tick
+    let modes: Vec<Mode> = (0..8 + 4 * 0xA0)
+        .map(|_| {
+            gb.context.tick();
+            gb.ppu_registers().mode
+        })
+        .collect();
+    tick_until_mode(&mut gb, Mode::VBlank1);
+
+    let oam: Vec<u8> = (0xFE00..0xFEA0).map(|a| gb.context.read(a)).collect();
+    let expected: Vec<u8> = pattern
+        .iter()
+        .enumerate()
+        .map(|(i, b)| match modes[11 + 4 * i] {
+            Mode::LCDOn3 => 0xFF,
+            _ => *b,
+        })
+        .collect();
+    assert_eq!(oam, expected);
+    assert_eq!(oam[0], 0xFF);
+    assert_eq!(oam[0x9F], 0x9F);
+}
+
+#[test]
+fn cartridge_without_ram() {
+    let mut gb = gameboy_with_program(&[]);
+    gb.take_events();
+    gb.context.write(0xA000, 0x12);
+    assert_eq!(gb.context.read(0xA000), 0xFF);
+    assert_eq!(gb.context.read(0xBFFF), 0xFF);
+    assert!(gb.unmapped_access_stats().is_empty());
+    assert!(gb.context.events.iter().all(|e| !matches!(
+        e,
+        ExecutionEvent::ReadFromNonMappedAddress(_) | ExecutionEvent::WriteToNonMappedAddress(_)
+    )));
+}
+
+#[test]
+fn unmapped_access_stats() {
+    let mut gb = gameboy_with_program(&[]);
+    gb.set_unmapped_events(false);
+    for _ in 0..5 {
+        gb.context.read(0xFF7F);
+    }
+    gb.context.write(0xFF7F, 0x12);
+    gb.context.write(0xFF03, 0x34);
+
+    let stats = gb.unmapped_access_stats();
+    assert_eq!(stats[&0xFF7F], (5, 1));
+    assert_eq!(stats[&0xFF03], (0, 1));
+    assert!(gb
+        .context
+        .events
+        .iter()
+        .all(|e| !matches!(e, ExecutionEvent::ReadFromNonMappedAddress(_))));
+
+    gb.clear_unmapped_stats();
+    assert!(gb.unmapped_access_stats().is_empty());
+    gb.set_unmapped_events(true);
+    gb.context.write(0xFF7F, 0x12);
+    assert!(gb
+        .context
+        .events
+        .iter()
+        .any(|e| matches!(e, ExecutionEvent::WriteToNonMappedAddress(_))));
+}
@@ -1,6 +1,6 @@
 use std::fs;
 
-use lib_gb_rs::{parse_into_cartridge, ExecutionEvent, GameBoy, Register8};
+use lib_gb_rs::{parse_into_cartridge, ExecutionEvent, GameBoy, MooneyeResult};
 
 const MAX_CYCLES: u64 = 10_000_000;
 
@@ -10,6 +10,7 @@ macro_rules! mooneye_tests {
     ($name_prefix:expr, $base_path:expr, $test_name:expr) => {
         paste::paste! {
             #[test]
+            #[allow(non_snake_case)]
             fn [<$name_prefix _ $test_name>]() {
                 mooneye_macro::test_rom(&format!("vendored_test_roms/mts-20211031-2031-86d1acf/{}{}.gb", $base_path, $test_name))
             }
@@ -39,13 +40,7 @@ pub fn test_rom(rom: &str) {
         res.unwrap();
         for e in events {
             if let ExecutionEvent::DebugTrigger = e {
-                let b = gb.cpu().read_register8(Register8::B);
-                let c = gb.cpu().read_register8(Register8::C);
-                let d = gb.cpu().read_register8(Register8::D);
-                let e = gb.cpu().read_register8(Register8::E);
-                let h = gb.cpu().read_register8(Register8::H);
-                let l = gb.cpu().read_register8(Register8::L);
-                assert_eq!([b, c, d, e, h, l], [3, 5, 8, 13, 21, 34]);
+                assert_eq!(gb.mooneye_result(), MooneyeResult::Pass, "{:?}", gb.cpu());
                 return;
             }
         }
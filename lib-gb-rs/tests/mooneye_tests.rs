@@ -17,9 +17,9 @@ mooneye_tests!(
     "tim10_div_trigger",
     "tim11",
     "tim11_div_trigger",
-    // "tima_reload",
-    // "tima_write_reloading",
-    // "tma_write_reloading",
+    "tima_reload",
+    "tima_write_reloading",
+    "tma_write_reloading",
 );
 
 mooneye_tests!("instructions", "acceptance/instr/", "daa");
@@ -33,3 +33,22 @@ mooneye_tests!(
 );
 
 mooneye_tests!("bits", "acceptance/bits/", "reg_f");
+
+mooneye_tests!(
+    "oam_dma",
+    "acceptance/",
+    "oam_dma_restart",
+    "oam_dma_start",
+    "oam_dma_timing",
+);
+
+mooneye_tests!(
+    "oam_dma_sources",
+    "acceptance/oam_dma/",
+    "basic",
+    "reg_read",
+    // Needs MBC5
+    // "sources-GS",
+);
+
+mooneye_tests!("boot", "acceptance/", "boot_regs-dmgABC");
@@ -0,0 +1,15 @@
+// Run with `cargo test --no-default-features`
+#![cfg(not(feature = "log"))]
+
+use lib_gb_rs::{parse_into_cartridge, GameBoy};
+
+#[test]
+fn runs_without_log() {
+    // Too short, padded with a warning that goes nowhere
+    let mut gb = GameBoy::new(parse_into_cartridge(vec![0; 0x200]));
+    for _ in 0..100 {
+        let (_, res) = gb.execute_operation();
+        res.unwrap();
+    }
+    assert!(gb.get_elapsed_cycles() > 0);
+}
@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+
+use lib_gb_rs::{parse_into_cartridge, ExecutionEvent, GameBoy, Register16};
+
+const TRACE_DIR: &str = "tests/traces";
+// Lines of already matching trace shown before a divergence
+const CONTEXT_LINES: usize = 4;
+
+const TRACED_REGISTERS: [(&str, Register16); 6] = [
+    ("PC", Register16::PC),
+    ("AF", Register16::AF),
+    ("BC", Register16::BC),
+    ("DE", Register16::DE),
+    ("HL", Register16::HL),
+    ("SP", Register16::SP),
+];
+
+// One line per executed instruction, with the registers as they are after it
+fn trace_rom(rom: Vec<u8>, instructions: usize) -> Vec<String> {
+    let mut gb = GameBoy::new(parse_into_cartridge(rom));
+    let mut trace = Vec::with_capacity(instructions);
+    while trace.len() < instructions {
+        let (events, res) = gb.execute_operation();
+        res.unwrap();
+        trace.extend(events.into_iter().filter_map(|e| {
+            match e {
+                ExecutionEvent::InstructionExecuted { cpu, .. } => Some(
+                    TRACED_REGISTERS
+                        .map(|(name, reg)| format!("{}:{:04X}", name, cpu.read_register16(reg)))
+                        .join(" "),
+                ),
+                _ => None,
+            }
+        }));
+    }
+    trace.truncate(instructions);
+    trace
+}
+
+// `name.gb` runs for as many instructions as `name.trace` has lines. Blank lines and lines starting
+// with `#` are skipped.
+fn assert_matches_reference(name: &str) {
+    let dir = Path::new(TRACE_DIR);
+    let rom = fs::read(dir.join(format!("{}.gb", name))).unwrap();
+    let reference = fs::read_to_string(dir.join(format!("{}.trace", name))).unwrap();
+    let reference: Vec<_> = reference
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+
+    let actual = trace_rom(rom, reference.len());
+    if let Some(i) = reference.iter().zip(&actual).position(|(r, a)| r != a) {
+        let context = actual[i.saturating_sub(CONTEXT_LINES)..i].join("\n");
+        panic!(
+            "{} diverges at instruction {}:\n{}\nexpected: {}\n  actual: {}",
+            name, i, context, reference[i], actual[i]
+        );
+    }
+}
+
+#[test]
+fn count_down() {
+    assert_matches_reference("count_down");
+}